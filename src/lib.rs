@@ -1,41 +1,176 @@
+#![cfg_attr(test, allow(clippy::clone_on_copy))]
+
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
 use std::borrow::Borrow;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cmp::Ordering;
 use std::collections::hash_map;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
 use std::hash::Hash;
 use std::iter::Chain;
 use std::iter::FromIterator;
-use std::ops::Index;
 use std::mem;
-use std::sync::mpsc::channel;
-use std::thread;
+use std::ops::AddAssign;
+use std::ops::{Deref, DerefMut};
+use std::ops::Index;
+use std::ops::RangeBounds;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Default)]
+#[cfg(feature = "rayon")]
+use rayon::collections::hash_map as par_hash_map;
+#[cfg(feature = "rayon")]
+use rayon::iter::plumbing::UnindexedConsumer;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// `Send` and `Sync` exactly when `K` and `V` are; the map never spawns
+/// threads of its own.
+///
+/// `Clone` copies both inner maps as they are, so a clone taken mid-rehash
+/// is mid-rehash too; `clone_compact` gives a settled copy instead.
+#[derive(Clone)]
 pub struct RehashingHashMap<K: Eq + Hash, V> {
     // NOTE: I tried to make an array of 2 elements, but run into borrowing problems
     hashmap1: HashMap<K, V>,
     hashmap2: HashMap<K, V>,
     is1main: bool,
     rehashing: bool,
+    auto_shrink: Option<f64>,
+    incremental_reserve: bool,
+    auto_grow: bool,
+    // (shrink below, grow above) load factors for should_rehash
+    rehash_thresholds: (f64, f64),
+    // load factor above which auto-grow kicks in; resizes aim below it
+    grow_watermark: Option<f64>,
+    capacity_policy: CapacityPolicy,
+    // &mut calls that skipped their rehash step since the last one that ran
+    #[cfg(debug_assertions)]
+    stalled_ops: usize,
 }
 
+// stalled_ops past which assert_state assumes the migration is stuck
+#[cfg(debug_assertions)]
+const STALL_LIMIT: usize = 10000;
+
 impl<K, V> RehashingHashMap<K, V>
-    where K: Eq + Hash + Clone
+    where K: Eq + Hash
 {
     pub fn new() -> RehashingHashMap<K, V> {
-        RehashingHashMap {
-            hashmap1: HashMap::new(),
-            hashmap2: HashMap::new(),
-            is1main: true,
-            rehashing: false,
-        }
+        RehashingHashMap::from_maps(HashMap::new(), HashMap::new(), true, false)
     }
 
     pub fn with_capacity(capacity: usize) -> RehashingHashMap<K, V> {
+        RehashingHashMap::from_maps(HashMap::with_capacity(capacity), HashMap::new(), true, false)
+    }
+
+    /// Sets the capacity of both inner maps. An idle secondary has to follow
+    /// the capacity policy, so a non-zero `secondary_capacity` becomes a
+    /// `CapacityPolicy::SecondaryRatio` of main's capacity, clamped to main's
+    /// capacity.
+    pub fn with_both_capacities(main_capacity: usize, secondary_capacity: usize) -> RehashingHashMap<K, V> {
+        let mut map = RehashingHashMap::with_capacity(main_capacity);
+        let capacity = map.get_main().capacity();
+        if secondary_capacity > 0 && capacity > 0 {
+            let ratio = (secondary_capacity as f64 / capacity as f64).min(1.0);
+            map.set_capacity_policy(CapacityPolicy::SecondaryRatio(ratio));
+        }
+        map
+    }
+
+    fn from_maps(hashmap1: HashMap<K, V>, hashmap2: HashMap<K, V>, is1main: bool, rehashing: bool) -> RehashingHashMap<K, V> {
         RehashingHashMap {
-            hashmap1: HashMap::with_capacity(capacity),
-            hashmap2: HashMap::new(),
-            is1main: true,
-            rehashing: false,
+            hashmap1,
+            hashmap2,
+            is1main,
+            rehashing,
+            auto_shrink: None,
+            incremental_reserve: false,
+            auto_grow: false,
+            rehash_thresholds: (0.25, 0.9),
+            grow_watermark: None,
+            capacity_policy: CapacityPolicy::Unallocated,
+            #[cfg(debug_assertions)]
+            stalled_ops: 0,
+        }
+    }
+
+    /// Like `from_iter`, but fails on the first key that shows up twice.
+    pub fn try_from_iter_unique<T>(iterable: T) -> Result<RehashingHashMap<K, V>, DuplicateKey<K>>
+            where T: IntoIterator<Item=(K, V)> {
+        let iter = iterable.into_iter();
+        let mut map = RehashingHashMap::with_capacity(iter.size_hint().0);
+        for (k, v) in iter {
+            if map.contains_key(&k) {
+                return Err(DuplicateKey(k));
+            }
+            map.insert_unique_unchecked(k, v);
+        }
+        Ok(map)
+    }
+
+    /// A clone that is not rehashing, with every entry in a single map sized
+    /// for `len()`.
+    pub fn clone_compact(&self) -> RehashingHashMap<K, V>
+            where K: Clone, V: Clone {
+        let mut map = RehashingHashMap::with_capacity(self.len());
+        map.hashmap1.extend(self.iter().map(|(k, v)| (k.clone(), v.clone())));
+        map.auto_shrink = self.auto_shrink;
+        map.incremental_reserve = self.incremental_reserve;
+        map.auto_grow = self.auto_grow;
+        map.rehash_thresholds = self.rehash_thresholds;
+        map.grow_watermark = self.grow_watermark;
+        map.capacity_policy = self.capacity_policy;
+        map
+    }
+
+    /// Returns `(hashmap1, hashmap2, is1main, rehashing)`.
+    pub fn into_inner_parts(self) -> (HashMap<K, V>, HashMap<K, V>, bool, bool) {
+        (self.hashmap1, self.hashmap2, self.is1main, self.rehashing)
+    }
+
+    /// The inverse of `into_inner_parts`. Fails if a key is in both maps, or
+    /// if the secondary has entries while not rehashing. A rehash whose
+    /// secondary is empty is taken as finished.
+    pub fn from_inner_parts(mut hashmap1: HashMap<K, V>, mut hashmap2: HashMap<K, V>, is1main: bool, rehashing: bool) -> Result<RehashingHashMap<K, V>, InvariantError<K>> {
+        {
+            let (small, large) = if hashmap1.len() <= hashmap2.len() {
+                (&mut hashmap1, &hashmap2)
+            } else {
+                (&mut hashmap2, &hashmap1)
+            };
+            // the maps are thrown away on error, so the key can be taken out
+            if let Some((k, _)) = small.extract_if(|k, _| large.contains_key(k)).next() {
+                return Err(InvariantError::OverlappingKeys(k));
+            }
+        }
+        let mut map = RehashingHashMap::from_maps(hashmap1, hashmap2, is1main, rehashing);
+        if !map.rehashing && !map.get_secondary().is_empty() {
+            return Err(InvariantError::SecondaryNotEmpty);
+        }
+        // a rehash with nothing left to migrate is over
+        if map.get_secondary().is_empty() {
+            map.drop_secondary();
+        }
+        Ok(map)
+    }
+
+    /// Checks the map's invariants, returning the first one that does not
+    /// hold instead of panicking.
+    pub fn validate(&self) -> Result<(), InvariantError<&K>> {
+        if !self.rehashing && !self.get_secondary().is_empty() {
+            return Err(InvariantError::SecondaryNotEmpty);
+        }
+        let (main, sec) = (self.get_main(), self.get_secondary());
+        let (small, large) = if main.len() <= sec.len() { (main, sec) } else { (sec, main) };
+        match small.keys().find(|k| large.contains_key(*k)) {
+            Some(k) => Err(InvariantError::OverlappingKeys(k)),
+            None => Ok(()),
         }
     }
 
@@ -55,35 +190,128 @@ impl<K, V> RehashingHashMap<K, V>
         if self.is1main { &mut self.hashmap2 } else { &mut self.hashmap1 }
     }
 
-    pub fn rehash(&mut self) {
+    // returns (main, secondary)
+    fn split_mut(&mut self) -> (&mut HashMap<K, V>, &mut HashMap<K, V>) {
+        if self.is1main {
+            (&mut self.hashmap1, &mut self.hashmap2)
+        } else {
+            (&mut self.hashmap2, &mut self.hashmap1)
+        }
+    }
+
+    /// Moves one entry to main. Returns true once there is nothing left to
+    /// migrate, so `while !map.rehash() {}` finishes a migration.
+    pub fn rehash(&mut self) -> bool {
         if self.rehashing {
-            if self.get_secondary().len() == 0 {
+            if self.get_secondary().is_empty() {
                 self.drop_secondary();
-                return;
+                return true;
             }
-            let (mut main, mut sec) = if self.is1main {
-                (&mut self.hashmap1, &mut self.hashmap2)
-            } else {
-                (&mut self.hashmap2, &mut self.hashmap1)
-            };
-            // unwrap is safe, checked len() > 0 already
-            let k: K = sec.keys().take(1).next().unwrap().clone();
-            // FIXME: I wish I did not have to clone they key
-            // unwrap is safe, we know the key exists in the hashmap
-            let val = sec.remove(&k).unwrap();
+            #[cfg(debug_assertions)]
+            { self.stalled_ops = 0; }
+            let (main, sec) = self.split_mut();
+            // unwrap is safe, checked is_empty() already
+            // dropping the ExtractIf after one element keeps the rest in place
+            let (k, val) = sec.extract_if(|_, _| true).next().unwrap();
             main.insert(k, val);
         }
+        !self.rehashing
+    }
+
+    /// A `rehash` step that only runs when moving a `V` costs at most
+    /// `max_cost`, using `size_of::<V>()` as the cost. Returns whether it ran.
+    pub fn rehash_if_cheap(&mut self, max_cost: usize) -> bool {
+        if !self.rehashing {
+            return false;
+        }
+        if mem::size_of::<V>() > max_cost {
+            self.note_stall();
+            return false;
+        }
+        self.rehash();
+        true
+    }
+
+    /// Moves everything left in the secondary to main in one go.
+    pub fn rehash_until_done(&mut self) {
+        if self.rehashing {
+            let (main, sec) = self.split_mut();
+            main.extend(sec.drain());
+            self.drop_secondary();
+        }
+    }
+
+    /// Gives up on migrating incrementally, leaving a single map with every
+    /// entry in it.
+    pub fn cancel_rehash(&mut self) {
+        self.rehash_until_done();
     }
 
     pub fn capacity(&self) -> usize {
         self.get_main().capacity() + self.get_secondary().len()
     }
 
+    /// The capacity the map will have once the current migration is done,
+    /// or `capacity()` when not rehashing. Main already has room for the
+    /// whole length unless incremental reserve is on, in which case this is
+    /// an estimate of what main will grow to.
+    pub fn target_capacity(&self) -> usize {
+        if !self.rehashing {
+            return self.capacity();
+        }
+        let capacity = self.get_main().capacity();
+        if self.incremental_reserve {
+            capacity.max(Self::allocated_capacity(self.len()))
+        } else {
+            capacity
+        }
+    }
+
     pub fn reserve(&mut self, additional: usize) {
         self.rehash();
+        if self.auto_grow && !self.rehashing {
+            let main = self.get_main();
+            let needed = main.len() + additional;
+            let above_watermark = match self.grow_watermark {
+                Some(high) => needed as f64 > high * main.capacity() as f64,
+                None => false,
+            };
+            if main.capacity() < needed || above_watermark {
+                let capacity = self.capacity_for(needed);
+                if main.is_empty() {
+                    // nothing to move, so main can just grow
+                    self.get_mut_main().reserve(capacity);
+                } else {
+                    // growing main in place would move every entry at once
+                    self.start_rehash(capacity);
+                }
+                return;
+            }
+        }
         self.get_mut_main().reserve(additional)
     }
 
+    /// `reserve`, returning the resulting `capacity()`.
+    pub fn reserve_returning(&mut self, additional: usize) -> usize {
+        self.reserve(additional);
+        self.capacity()
+    }
+
+    /// When set, a `reserve` that does not fit in main starts migrating into
+    /// a bigger map instead of growing main in one go.
+    pub fn set_auto_grow(&mut self, auto_grow: bool) {
+        self.auto_grow = auto_grow;
+    }
+
+    /// Like `reserve`, but while rehashing it also reserves on the secondary.
+    /// Outside of a rehash the secondary is left alone.
+    pub fn reserve_both(&mut self, additional: usize) {
+        self.reserve(additional);
+        if self.rehashing {
+            self.get_mut_secondary().reserve(additional);
+        }
+    }
+
     pub fn is_rehashing(&self) -> bool {
         if !self.rehashing {
             assert_eq!(self.get_secondary().len(), 0);
@@ -91,34 +319,272 @@ impl<K, V> RehashingHashMap<K, V>
         self.rehashing
     }
 
+    /// Starts migrating into a map sized for the current length. Does nothing
+    /// if that map would end up with more capacity than main has now, which
+    /// can happen when removals left main's capacity below a fresh map's.
+    ///
+    /// During a grow, such as one started by auto-grow, into a bigger map
+    /// than the length needs, that migration is finished first and then the
+    /// shrink starts. Any other migration in progress is left alone.
     pub fn shrink_to_fit(&mut self) {
+        if self.rehashing {
+            let main_capacity = self.get_main().capacity();
+            let is_grow = main_capacity > self.get_secondary().capacity();
+            if !is_grow || Self::allocated_capacity(self.capacity_for(self.len())) >= main_capacity {
+                return;
+            }
+            self.rehash_until_done();
+        }
+        let (capacity, target_capacity) = self.shrink_target();
+        self.start_shrink(capacity, target_capacity);
+    }
+
+    /// Like `shrink_to_fit`, but only starts a migration that would lower
+    /// main's capacity. Returns whether one was started.
+    pub fn shrink_to_fit_checked(&mut self) -> bool {
+        let is1main = self.is1main;
+        if self.rehashing {
+            self.shrink_to_fit();
+        } else {
+            let (capacity, target_capacity) = self.shrink_target();
+            if target_capacity >= self.get_main().capacity() {
+                return false;
+            }
+            self.start_shrink(capacity, target_capacity);
+        }
+        // every migration start swaps the maps
+        self.rehashing && self.is1main != is1main
+    }
+
+    // the capacity a new map asked for `capacity` entries actually gets.
+    // Past the smallest tables, whose sizing depends on the entry size, std
+    // uses a power of two buckets at most 7/8 full, so this is worked out
+    // instead of allocating a table the size of the map
+    fn allocated_capacity(capacity: usize) -> usize {
+        if capacity < 15 {
+            HashMap::<K, V>::with_capacity(capacity).capacity()
+        } else {
+            (capacity.saturating_mul(8) / 7).next_power_of_two() / 8 * 7
+        }
+    }
+
+    // the capacity a shrink asks for, and what the new main gets for it
+    fn shrink_target(&self) -> (usize, usize) {
+        let capacity = if self.incremental_reserve { 0 } else { self.capacity_for(self.len()) };
+        (capacity, Self::allocated_capacity(capacity))
+    }
+
+    // shrink_to_fit once no migration is running
+    fn start_shrink(&mut self, capacity: usize, target_capacity: usize) {
+        if self.is_empty() {
+            // nothing to migrate, just let go of main's allocation
+            *self.get_mut_main() = HashMap::new();
+            self.resize_idle_secondary();
+            return;
+        }
+        let main_capacity = self.get_main().capacity();
+        if target_capacity > main_capacity {
+            return;
+        }
+        // the secondary is empty while not rehashing. One kept allocated by
+        // the capacity policy is reused if it is big enough, and at most one
+        // growth step bigger than needed
+        let secondary_capacity = self.get_secondary().capacity();
+        if secondary_capacity < target_capacity || secondary_capacity >= 2 * target_capacity.max(1)
+                || secondary_capacity > main_capacity {
+            *self.get_mut_secondary() = HashMap::with_capacity(capacity);
+        }
+        self.start_rehash(0);
+    }
+
+    /// `shrink_to_fit`, then moves up to `eager_steps` entries right away.
+    pub fn shrink_to_fit_migrating(&mut self, eager_steps: usize) {
+        self.shrink_to_fit();
+        for _ in 0..eager_steps {
+            if self.rehash() {
+                break;
+            }
+        }
+    }
+
+    /// `shrink_to_fit` followed by `rehash_until_done`, reporting
+    /// `estimated_memory_usage` before and after.
+    pub fn shrink_to_fit_reporting(&mut self) -> CompactionReport {
+        let before = self.estimated_memory_usage();
+        self.shrink_to_fit();
+        self.rehash_until_done();
+        let after = self.estimated_memory_usage();
+        CompactionReport {
+            before,
+            after,
+            reclaimed: before.saturating_sub(after),
+        }
+    }
+
+    // swaps main and secondary, so everything is left to be migrated into a
+    // new main with room for `capacity` entries
+    fn start_rehash(&mut self, capacity: usize) {
+        self.rehashing = true;
+        self.is1main = !self.is1main;
+        self.get_mut_main().reserve(capacity)
+    }
+
+    /// When set, `shrink_to_fit` does not reserve the whole length up front.
+    /// Main grows as entries are moved into it, so it never holds more than
+    /// it needs, at the cost of some reallocations. The secondary keeps its
+    /// allocation until the migration ends, since shrinking it would rehash
+    /// every entry left in it in a single step.
+    pub fn set_incremental_reserve(&mut self, incremental: bool) {
+        self.incremental_reserve = incremental;
+    }
+
+    /// When set, `remove` starts a `shrink_to_fit` once `len() / capacity()`
+    /// drops below the threshold.
+    pub fn set_auto_shrink(&mut self, threshold: Option<f64>) {
+        self.auto_shrink = threshold;
+    }
+
+    /// Turns on auto-shrink below `low` and, when auto-grow is set, grows
+    /// once a `reserve` would take main above `high`. Resizes then size the
+    /// new map to stay below `high`, so that a map whose load factor stays
+    /// between the two never rehashes. Also sets the `should_rehash`
+    /// thresholds.
+    ///
+    /// std counts the tombstones that removals leave behind against
+    /// `capacity()`, so after many removals the load factor seen here can be
+    /// higher than the real one.
+    pub fn set_rehash_watermarks(&mut self, low: f64, high: f64) {
+        assert!(0.0 <= low && low < high && high <= 1.0, "watermarks must satisfy 0 <= low < high <= 1");
+        self.auto_shrink = Some(low);
+        self.grow_watermark = Some(high);
+        self.rehash_thresholds = (low, high);
+    }
+
+    // the capacity to resize to for `len` entries, leaving room below the
+    // grow watermark if there is one
+    fn capacity_for(&self, len: usize) -> usize {
+        match self.grow_watermark {
+            Some(high) => (len as f64 / high).ceil() as usize,
+            None => len,
+        }
+    }
+
+    /// Sets how big the secondary is kept while not rehashing. Applied right
+    /// away when not rehashing, and whenever a rehash ends.
+    pub fn set_capacity_policy(&mut self, policy: CapacityPolicy) {
+        if let CapacityPolicy::SecondaryRatio(ratio) = policy {
+            assert!((0.0..=1.0).contains(&ratio), "the secondary ratio must be between 0 and 1");
+        }
+        self.capacity_policy = policy;
         if !self.rehashing {
-            self.rehashing = true;
-            self.is1main = !self.is1main;
-            let len = self.len();
-            self.get_mut_main().reserve(len)
+            self.resize_idle_secondary();
+        }
+    }
+
+    /// Sets the load factors `should_rehash` compares against. Defaults to
+    /// 0.25 and 0.9.
+    pub fn set_rehash_thresholds(&mut self, shrink_below: f64, grow_above: f64) {
+        self.rehash_thresholds = (shrink_below, grow_above);
+    }
+
+    /// Recommends a resize based on main's load factor. Never mutates, and
+    /// returns `None` while a rehash is already in progress.
+    pub fn should_rehash(&self) -> Option<RehashReason> {
+        if self.rehashing {
+            return None;
+        }
+        let stats = self.bucket_stats();
+        let (shrink_below, grow_above) = self.rehash_thresholds;
+        if stats.capacity == 0 {
+            None
+        } else if stats.load_factor > grow_above {
+            Some(RehashReason::Grow)
+        } else if stats.load_factor < shrink_below {
+            Some(RehashReason::Shrink)
+        } else {
+            None
+        }
+    }
+
+    /// Rough heap usage of both inner maps, one `(K, V)` and a control byte
+    /// per slot of capacity. std does not expose its bucket count, so the
+    /// real figure is somewhat higher.
+    pub fn estimated_memory_usage(&self) -> usize {
+        let slots = self.get_main().capacity() + self.get_secondary().capacity();
+        slots * (mem::size_of::<(K, V)>() + 1)
+    }
+
+    /// Occupancy of the main map. std's `HashMap` does not expose its buckets,
+    /// so this cannot report probe lengths or the real distribution.
+    pub fn bucket_stats(&self) -> BucketStats {
+        let main = self.get_main();
+        BucketStats {
+            capacity: main.capacity(),
+            len: main.len(),
+            load_factor: if main.capacity() == 0 { 0.0 } else { main.len() as f64 / main.capacity() as f64 },
         }
     }
 
+    /// O(1), the sum of both inner maps' lengths.
     pub fn len(&self) -> usize {
         self.get_main().len() + self.get_secondary().len()
     }
 
+    pub fn len_cmp(&self, other: &RehashingHashMap<K, V>) -> Ordering {
+        self.len().cmp(&other.len())
+    }
+
+    /// O(1), like `len`.
     pub fn is_empty(&self) -> bool {
         self.get_main().is_empty() && self.get_secondary().is_empty()
     }
 
+    // a rehash step was skipped while rehashing, only tracked in debug builds
+    fn note_stall(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            if self.rehashing {
+                self.stalled_ops += 1;
+            }
+        }
+    }
+
     fn drop_secondary(&mut self) {
         self.rehashing = false;
+        #[cfg(debug_assertions)]
+        { self.stalled_ops = 0; }
         assert_eq!(self.get_secondary().len(), 0);
-        let h = if self.is1main {
-            mem::replace(&mut self.hashmap2, HashMap::new());
-        } else {
-            mem::replace(&mut self.hashmap1, HashMap::new());
+        self.resize_idle_secondary();
+    }
+
+    // sizes the empty secondary of a map that is not rehashing as the
+    // capacity policy asks
+    fn resize_idle_secondary(&mut self) {
+        let capacity = match self.capacity_policy {
+            CapacityPolicy::Unallocated => 0,
+            CapacityPolicy::SecondaryRatio(ratio) => (self.get_main().capacity() as f64 * ratio) as usize,
         };
-        let (tx, rx) = channel();
-        thread::spawn(move || drop(rx.recv().unwrap()));
-        tx.send(h).unwrap();
+        let sec = self.get_mut_secondary();
+        if capacity == 0 {
+            *sec = HashMap::new();
+        } else if sec.capacity() > capacity {
+            sec.shrink_to(capacity);
+        } else {
+            sec.reserve(capacity);
+        }
+    }
+
+    // moves `k` from the secondary to main, if it is there. Returns whether
+    // it was
+    fn consolidate<Q>(&mut self, k: &Q) -> bool
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        if self.rehashing {
+            if let Some((k, v)) = self.get_mut_secondary().remove_entry(k) {
+                self.get_mut_main().insert(k, v);
+                return true;
+            }
+        }
+        false
     }
 
     fn assert_state(&self) {
@@ -126,35 +592,163 @@ impl<K, V> RehashingHashMap<K, V>
         if self.rehashing {
             assert!(self.get_secondary().capacity() > 0);
         } else {
-            assert!(self.get_secondary().capacity() == 0);
+            assert!(self.get_secondary().is_empty());
+            if self.capacity_policy == CapacityPolicy::Unallocated {
+                assert!(self.get_secondary().capacity() == 0);
+            }
+        }
+        #[cfg(debug_assertions)]
+        assert!(self.stalled_ops < STALL_LIMIT, "rehash has not advanced in {} operations", self.stalled_ops);
+    }
+
+    /// Restores the internal invariants after a panic (e.g. in a key's `Hash`
+    /// impl) interrupted an operation. The map is always left usable by such
+    /// a panic, at worst in a rehashing state that can simply be resumed, so
+    /// this is only needed to get a fully consistent state back.
+    pub fn recover(&mut self) {
+        // main holds the most recent writes, so it wins on overlapping keys
+        let (main, sec) = self.split_mut();
+        sec.retain(|k, _| !main.contains_key(k));
+
+        if self.get_secondary().is_empty() {
+            if self.rehashing || self.get_secondary().capacity() > 0 {
+                self.drop_secondary();
+            }
+        } else {
+            self.rehashing = true;
         }
     }
 
     pub fn clear(&mut self) {
+        if !self.rehashing && self.is_empty() {
+            return;
+        }
         self.get_mut_main().clear();
+        self.get_mut_secondary().clear();
         self.drop_secondary();
     }
 
+    /// Adds every entry of `map`. An empty map that is not rehashing takes
+    /// `map` over as main as is, without rehashing its entries.
+    pub fn merge_from_hashmap(&mut self, map: HashMap<K, V>) {
+        if self.is_empty() && !self.rehashing {
+            self.hashmap1 = map;
+            self.is1main = true;
+            self.resize_idle_secondary();
+        } else {
+            self.extend(map);
+        }
+    }
+
+    /// Swaps in the entries from `iter` in place of the current ones. Main
+    /// keeps its allocation and is sized once for `iter`, and the map ends
+    /// up not rehashing.
+    pub fn replace_contents<T>(&mut self, iter: T)
+            where T: IntoIterator<Item=(K, V)> {
+        self.clear();
+        let iter = iter.into_iter();
+        let main = self.get_mut_main();
+        main.reserve(iter.size_hint().0);
+        main.extend(iter);
+    }
+
+    /// Empties the map, yielding its entries. Entries left when the iterator
+    /// is dropped are removed anyway, like `HashMap::drain`.
+    pub fn drain(&mut self) -> Drain<'_, K, V> {
+        // a secondary being migrated is taken whole, so the map ends up not
+        // rehashing no matter how much of the drain is consumed. An idle one
+        // is empty and keeps the allocation the capacity policy gave it
+        let secondary = if self.rehashing {
+            let secondary = mem::take(self.get_mut_secondary());
+            self.drop_secondary();
+            secondary
+        } else {
+            HashMap::new()
+        };
+        let len = self.get_main().len() + secondary.len();
+        Drain {
+            inner: self.get_mut_main().drain().chain(secondary),
+            len,
+        }
+    }
+
+    /// Like `drain`, but yields only the keys and drops the values.
+    pub fn drain_keys(&mut self) -> impl Iterator<Item=K> + '_ {
+        self.drain().map(|(k, _)| k)
+    }
+
+    /// Moves every entry into a new `HashMap`, leaving this map empty but
+    /// with main's allocation kept for reuse.
+    pub fn take_all(&mut self) -> HashMap<K, V> {
+        let mut all = HashMap::with_capacity(self.len());
+        all.extend(self.drain());
+        all
+    }
+
     pub fn insert(&mut self, k: K, v: V) -> Option<V> {
-        // while rehashing, they key can be in either hashmap1 or hashmap2
-        // but we want to remove them from wherever it is and add it to main
-        let mut ret = None;
-        if self.rehashing || self.is1main {
-            ret = self.hashmap1.remove(&k);
+        // while rehashing, the key can still be in the secondary, but we want
+        // it only in main. Otherwise the secondary is empty and not probed.
+        let secondary = if self.rehashing {
+            self.get_mut_secondary().remove(&k)
+        } else {
+            None
+        };
+        if self.rehashing && !self.incremental_reserve {
+            // keep room for whatever is still in the secondary, so moving it
+            // to main never makes main grow
+            let len = self.len() + 1;
+            let main = self.get_mut_main();
+            let additional = len - main.len();
+            main.reserve(additional);
+        }
+        let ret = self.get_mut_main().insert(k, v).or(secondary);
+        self.rehash();
+        ret
+    }
+
+    /// Inserts every pair, returning the values they displaced. A key
+    /// repeated in `entries` keeps the value it had before the batch, so the
+    /// result is enough to roll the batch back.
+    pub fn insert_all<T>(&mut self, entries: T) -> HashMap<K, V>
+            where T: IntoIterator<Item=(K, V)>, K: Clone {
+        let mut displaced = HashMap::new();
+        for (k, v) in entries {
+            if let Some(old) = self.insert(k.clone(), v) {
+                displaced.entry(k).or_insert(old);
+            }
         }
-        if ret.is_none() && (self.rehashing || !self.is1main) {
-            ret = self.hashmap2.remove(&k);
+        displaced
+    }
+
+    /// Like `insert`, but a new key is rejected with `Err(v)` once the map
+    /// holds `max_len` entries. Updating an existing key always succeeds.
+    pub fn insert_bounded(&mut self, k: K, v: V, max_len: usize) -> Result<Option<V>, V> {
+        if self.len() >= max_len && !self.contains_key(&k) {
+            return Err(v);
         }
+        Ok(self.insert(k, v))
+    }
+
+    /// Like `insert`, also returning whether a rehash step ran, which is
+    /// whenever the map was rehashing.
+    pub fn insert_tracked(&mut self, k: K, v: V) -> (Option<V>, bool) {
+        let stepped = self.rehashing;
+        (self.insert(k, v), stepped)
+    }
+
+    /// Inserts without first looking for `k` in the other map. The caller
+    /// must know `k` is not in the map; otherwise it may end up stored twice,
+    /// which is memory safe but leaves the map logically inconsistent.
+    pub fn insert_unique_unchecked(&mut self, k: K, v: V) {
         self.get_mut_main().insert(k, v);
         self.rehash();
-        ret
     }
 
-    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
-            where K: Borrow<Q>, Q: Hash + Eq {
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
         if self.rehashing {
             match self.get_main().get(k) {
-                Some(ref v) => Some(v),
+                Some(v) => Some(v),
                 None => self.get_secondary().get(k),
             }
         } else {
@@ -162,82 +756,617 @@ impl<K, V> RehashingHashMap<K, V>
         }
     }
 
-    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
-            where K: Borrow<Q>, Q: Hash + Eq {
+    pub fn get_with_side<Q>(&self, k: &Q) -> Option<(&V, MapSide)>
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        match self.get_main().get(k) {
+            Some(v) => Some((v, MapSide::Main)),
+            None => self.get_secondary().get(k).map(|v| (v, MapSide::Secondary)),
+        }
+    }
+
+    /// Like `get`, with a flag that is true when the value is still waiting
+    /// to be migrated out of the secondary.
+    pub fn get_pending<Q>(&self, k: &Q) -> Option<(&V, bool)>
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        self.get_with_side(k).map(|(v, side)| (v, side == MapSide::Secondary))
+    }
+
+    /// `(in_main, in_secondary)` for `k`. Both being true means the map is
+    /// corrupt.
+    pub fn debug_key_state<Q>(&self, k: &Q) -> (bool, bool)
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        (self.get_main().contains_key(k), self.get_secondary().contains_key(k))
+    }
+
+    pub fn get_or<'a, Q>(&'a self, k: &Q, default: &'a V) -> &'a V
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        self.get(k).unwrap_or(default)
+    }
+
+    pub fn get_cow<Q>(&self, k: &Q) -> Option<Cow<'_, V>>
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq, V: Clone {
+        self.get(k).map(Cow::Borrowed)
+    }
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
         if self.rehashing {
             self.rehash();
-            if self.get_main().contains_key(k) {
-                self.get_mut_main().get_mut(k)
-            } else {
-                self.get_mut_secondary().get_mut(k)
+            let (main, sec) = self.split_mut();
+            match main.get_mut(k) {
+                Some(v) => Some(v),
+                None => sec.get_mut(k),
             }
         } else {
             self.get_mut_main().get_mut(k)
         }
     }
 
-    pub fn contains_key<Q: ?Sized>(&self, k: &Q) -> bool
-            where K: Borrow<Q>, Q: Hash + Eq {
+    /// Like `get_mut`, but never advances the rehash nor moves the entry, so
+    /// a value in the secondary stays there.
+    pub fn get_mut_in_place<Q>(&mut self, k: &Q) -> Option<&mut V>
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        self.note_stall();
+        let (main, sec) = self.split_mut();
+        match main.get_mut(k) {
+            Some(v) => Some(v),
+            None => sec.get_mut(k),
+        }
+    }
+
+    /// Like `get_mut`, but the rehash step runs when the guard is dropped
+    /// instead of up front. The entry is moved out of the map while the guard
+    /// lives and put back into main on drop, so leaking the guard loses it.
+    pub fn get_mut_guard<Q>(&mut self, k: &Q) -> Option<ValueGuard<'_, K, V>>
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        let (main, sec) = self.split_mut();
+        let entry = match main.remove_entry(k) {
+            Some(e) => e,
+            None => sec.remove_entry(k)?,
+        };
+        Some(ValueGuard {
+            map: self,
+            entry: Some(entry),
+        })
+    }
+
+    /// Swaps `new` in for the value under `k` and returns the old one. Unlike
+    /// `insert`, a missing key is not added. Like `get_mut_in_place`, the
+    /// entry stays in whichever map holds it.
+    pub fn replace_value<Q>(&mut self, k: &Q, new: V) -> Option<V>
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        self.get_mut_in_place(k).map(|v| mem::replace(v, new))
+    }
+
+    pub fn update<Q, F>(&mut self, k: &Q, f: F) -> bool
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq, F: FnOnce(&mut V) {
+        self.rehash();
+        self.consolidate(k);
+        match self.get_mut_main().get_mut(k) {
+            Some(v) => {
+                f(v);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn with_values_mut<Q, F>(&mut self, keys: &[&Q], mut f: F)
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq, F: FnMut(&K, &mut V) {
+        self.rehash();
+        for k in keys {
+            // HashMap cannot hand out `(&K, &mut V)`, so take the entry out
+            // and put it back in main, consolidating it on the way. The guard
+            // puts it back even if `f` panics
+            let entry = match self.get_mut_main().remove_entry(*k) {
+                Some(entry) => Some(entry),
+                None if self.rehashing => self.get_mut_secondary().remove_entry(*k),
+                None => None,
+            };
+            if entry.is_some() {
+                let mut guard = Reinsert {
+                    map: self.get_mut_main(),
+                    entry,
+                };
+                if let Some((ref k, ref mut v)) = guard.entry {
+                    f(k, v);
+                }
+            }
+        }
+    }
+
+    /// Mutable references to the values of several distinct keys at once.
+    /// Every key found is moved to main first.
+    pub fn try_get_many_mut<Q, const N: usize>(&mut self, ks: [&Q; N]) -> Result<[&mut V; N], GetManyMutError>
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        for i in 0..N {
+            for j in (i + 1)..N {
+                if ks[i] == ks[j] {
+                    return Err(GetManyMutError::DuplicateKeys(i, j));
+                }
+            }
+        }
+        self.rehash();
+        for k in ks.iter() {
+            self.consolidate(*k);
+        }
+        let values = self.get_mut_main().get_disjoint_mut(ks);
+        let missing: Vec<usize> = values.iter().enumerate()
+            .filter(|&(_, v)| v.is_none())
+            .map(|(i, _)| i)
+            .collect();
+        if !missing.is_empty() {
+            return Err(GetManyMutError::MissingKeys(missing));
+        }
+        // unwrap is safe, checked there are no missing keys
+        Ok(values.map(|v| v.unwrap()))
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
         self.get_main().contains_key(k) || self.get_secondary().contains_key(k)
     }
 
-    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
-        where K: Borrow<Q>, Q: Hash + Eq {
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        self.remove_with_side(k).map(|(v, _)| v)
+    }
+
+    /// Like `remove`, but also reports which map the value came from. The
+    /// rehash step runs before the lookup, so a key migrated by that step is
+    /// reported as `Main`.
+    pub fn remove_with_side<Q>(&mut self, k: &Q) -> Option<(V, MapSide)>
+        where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
         if self.rehashing {
             self.rehash();
             match self.get_mut_main().remove(k) {
-                Some(v) => Some(v),
-                None => self.get_mut_secondary().remove(k),
+                Some(v) => Some((v, MapSide::Main)),
+                None => self.get_mut_secondary().remove(k).map(|v| (v, MapSide::Secondary)),
             }
         } else {
-            self.get_mut_main().remove(k)
+            let ret = self.get_mut_main().remove(k);
+            if ret.is_some() {
+                self.maybe_auto_shrink();
+            }
+            ret.map(|v| (v, MapSide::Main))
         }
     }
 
-    pub fn entry(&mut self, key: K) -> hash_map::Entry<K, V> {
-        self.rehash();
-        if self.rehashing {
-            if self.get_secondary().contains_key(&key) {
-                return self.get_mut_secondary().entry(key);
+    /// Moves the value under `from` to `to`, overwriting any value `to`
+    /// already had. Returns whether `from` was present.
+    pub fn rename<Q>(&mut self, from: &Q, to: K) -> bool
+        where K: Borrow<Q>, Q: ?Sized + Hash + Eq {
+        match self.remove(from) {
+            Some(v) => {
+                self.insert(to, v);
+                true
             }
+            None => false,
         }
-        self.get_mut_main().entry(key)
     }
 
-    pub fn iter(&self) -> Iter<K, V> {
-        Iter {
-            inner: self.hashmap1.iter().chain(self.hashmap2.iter()),
-            len: self.hashmap1.len() + self.hashmap2.len(),
+    fn maybe_auto_shrink(&mut self) {
+        if let Some(threshold) = self.auto_shrink {
+            let capacity = self.capacity();
+            if !self.rehashing && capacity > 0 && (self.len() as f64) / (capacity as f64) < threshold {
+                // a shrink that does not reclaim anything would just start
+                // over on the next remove
+                self.shrink_to_fit_checked();
+            }
         }
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<K, V> {
-        self.rehash();
-        let len = self.hashmap1.len() + self.hashmap2.len();
-        IterMut {
-            inner: self.hashmap1.iter_mut().chain(self.hashmap2.iter_mut()),
-            len: len,
+    /// Starts a `shrink_to_fit` if not rehashing and `len() / capacity()` is
+    /// below `threshold`. Returns whether it did.
+    pub fn compact_if_sparse(&mut self, threshold: f64) -> bool {
+        let capacity = self.capacity();
+        if !self.rehashing && capacity > 0 && (self.len() as f64) / (capacity as f64) < threshold {
+            self.shrink_to_fit_checked()
+        } else {
+            false
         }
     }
 
-    pub fn keys(&self) -> Keys<K, V> {
-        Keys {
-            inner: self.hashmap1.keys().chain(self.hashmap2.keys()),
-            len: self.hashmap1.len() + self.hashmap2.len(),
+    /// Runs rehash steps until the migration is done or `budget` runs out.
+    pub fn rehash_for(&mut self, budget: Duration) {
+        let start = Instant::now();
+        while self.rehashing && start.elapsed() < budget {
+            self.rehash();
         }
     }
 
-    pub fn values(&self) -> Values<K, V> {
-        Values {
-            inner: self.hashmap1.values().chain(self.hashmap2.values()),
-            len: self.hashmap1.len() + self.hashmap2.len(),
+    /// Migrates until at most `max_secondary` entries are left to move, and
+    /// returns how many were moved.
+    pub fn rehash_to_cap(&mut self, max_secondary: usize) -> usize {
+        let remaining = self.remaining_to_rehash();
+        while self.rehashing && self.remaining_to_rehash() > max_secondary {
+            self.rehash();
         }
+        remaining - self.remaining_to_rehash()
     }
-}
 
-impl<K, V> PartialEq for RehashingHashMap<K, V> where K: Eq + Hash + Clone, V: PartialEq {
-    fn eq(&self, other: &RehashingHashMap<K, V>) -> bool {
-        // we cannot rehash because `self` and `other` are not immutables!
+    /// One call per tick of an event loop: advances a migration in progress
+    /// for up to `budget`, or starts one if the map is idle and sparse.
+    pub fn maintenance_tick(&mut self, budget: Duration, sparse_threshold: f64) {
+        if self.rehashing {
+            self.rehash_for(budget);
+        } else {
+            self.compact_if_sparse(sparse_threshold);
+        }
+    }
+
+    /// Returns how many entries were removed.
+    pub fn retain<F>(&mut self, mut f: F) -> usize
+            where F: FnMut(&K, &mut V) -> bool {
+        let len = self.len();
+        let (main, sec) = self.split_mut();
+        main.retain(&mut f);
+        // every secondary entry gets visited anyway, so finish the migration
+        // with the ones that survive
+        for (k, mut v) in sec.drain() {
+            if f(&k, &mut v) {
+                main.insert(k, v);
+            }
+        }
+        if self.rehashing {
+            self.drop_secondary();
+        }
+        len - self.len()
+    }
+
+    /// Like `retain`, but `f` can end the scan early with `StopKeep` or
+    /// `StopRemove`, and is not called again after that. A migration in
+    /// progress is left as is. Returns how many entries were removed.
+    pub fn retain_until<F>(&mut self, mut f: F) -> usize
+            where F: FnMut(&K, &mut V) -> RetainAction {
+        let len = self.len();
+        // Some(keep) once f asked to stop. Stopping entries are extracted too,
+        // so the scan can end right there, and are put back if kept
+        let stop = Cell::new(None);
+        let mut pred = |k: &K, v: &mut V| {
+            match f(k, v) {
+                RetainAction::Keep => false,
+                RetainAction::Remove => true,
+                RetainAction::StopKeep => {
+                    stop.set(Some(true));
+                    true
+                }
+                RetainAction::StopRemove => {
+                    stop.set(Some(false));
+                    true
+                }
+            }
+        };
+        let (main, sec) = self.split_mut();
+        for map in [main, sec] {
+            let kept = {
+                let mut removed = map.extract_if(&mut pred);
+                loop {
+                    match removed.next() {
+                        Some(entry) => if let Some(keep) = stop.get() {
+                            break if keep { Some(entry) } else { None };
+                        },
+                        None => break None,
+                    }
+                }
+            };
+            if let Some((k, v)) = kept {
+                map.insert(k, v);
+            }
+            if stop.get().is_some() {
+                break;
+            }
+        }
+        if self.rehashing && self.get_secondary().is_empty() {
+            self.drop_secondary();
+        }
+        len - self.len()
+    }
+
+    /// Removes and returns every entry with a key in `range`. O(n), as both
+    /// maps are scanned; like `retain`, this finishes any migration.
+    pub fn drain_range<R>(&mut self, range: R) -> Vec<(K, V)>
+            where K: Ord, R: RangeBounds<K> {
+        let (main, sec) = self.split_mut();
+        let mut drained: Vec<(K, V)> = main.extract_if(|k, _| range.contains(k)).collect();
+        for (k, v) in sec.drain() {
+            if range.contains(&k) {
+                drained.push((k, v));
+            } else {
+                main.insert(k, v);
+            }
+        }
+        if self.rehashing {
+            self.drop_secondary();
+        }
+        drained
+    }
+
+    /// Removes every entry matching `pred`, returning how many there were.
+    pub fn remove_where<F>(&mut self, mut pred: F) -> usize
+            where F: FnMut(&K, &V) -> bool {
+        self.retain(|k, v| !pred(k, v))
+    }
+
+    pub fn entry(&mut self, key: K) -> hash_map::Entry<'_, K, V> {
+        self.entry_tracked(key).0
+    }
+
+    /// Like `entry`, also returning whether the key had to be moved out of
+    /// the secondary.
+    pub fn entry_tracked(&mut self, key: K) -> (hash_map::Entry<'_, K, V>, bool) {
+        if !self.rehashing {
+            return (self.get_mut_main().entry(key), false);
+        }
+        // always hand out an entry into main, so the entry's key is the one
+        // that stays in the map. Consolidating before the rehash step means a
+        // move done by that step is reported too
+        let moved = self.consolidate(&key);
+        self.rehash();
+        (self.get_mut_main().entry(key), moved)
+    }
+
+    pub fn get_or_insert(&mut self, k: K, default: V) -> &V {
+        self.entry(k).or_insert(default)
+    }
+
+    /// Calls `f` only when `k` is missing, and inserts its value on `Ok`. On
+    /// `Err` nothing is inserted, though the rehash step still runs.
+    pub fn get_or_try_insert_with<E, F>(&mut self, k: K, f: F) -> Result<&mut V, E>
+            where F: FnOnce() -> Result<V, E> {
+        match self.entry(k) {
+            hash_map::Entry::Occupied(e) => Ok(e.into_mut()),
+            hash_map::Entry::Vacant(e) => Ok(e.insert(f()?)),
+        }
+    }
+
+    /// Applies `update` to the value under `k`, or inserts `default` if
+    /// there is none.
+    pub fn modify_or_insert<F>(&mut self, k: K, update: F, default: V)
+            where F: FnOnce(&mut V) {
+        self.entry(k).and_modify(update).or_insert(default);
+    }
+
+    /// Applies `f` to the value under `k`, inserting `V::default()` first
+    /// if missing. Handy for multimaps, e.g. pushing onto a `Vec` value.
+    pub fn modify_or_insert_default<F>(&mut self, k: K, f: F)
+            where V: Default, F: FnOnce(&mut V) {
+        f(self.entry(k).or_default());
+    }
+
+    /// Adds `by` to the value under `k`, starting from `V::default()`, and
+    /// returns the new total.
+    pub fn increment(&mut self, k: K, by: V) -> &V
+            where V: AddAssign + Default {
+        let v = self.entry(k).or_default();
+        *v += by;
+        v
+    }
+
+    /// An entry into the secondary map, for rebuilding a map mid-migration.
+    /// `None` when not rehashing or when `key` is already in main, since a
+    /// key must never be in both maps.
+    pub fn entry_secondary(&mut self, key: K) -> Option<hash_map::Entry<'_, K, V>> {
+        if !self.rehashing || self.get_main().contains_key(&key) {
+            return None;
+        }
+        self.note_stall();
+        Some(self.get_mut_secondary().entry(key))
+    }
+
+    /// Runs `update` on the value under `k` if there is one, otherwise
+    /// inserts `insert()`.
+    pub fn upsert<F, G>(&mut self, k: K, insert: F, update: G)
+            where F: FnOnce() -> V, G: FnOnce(&mut V) {
+        match self.entry(k) {
+            hash_map::Entry::Occupied(mut e) => update(e.get_mut()),
+            hash_map::Entry::Vacant(e) => {
+                e.insert(insert());
+            }
+        }
+    }
+
+    /// Like `entry`, but looks `probe` up and only calls `make_key` when the
+    /// key has to be inserted. `make_key` must build a key equal to `probe`.
+    pub fn entry_with<Q, F>(&mut self, probe: &Q, make_key: F) -> EntryWith<'_, K, V, F>
+            where K: Borrow<Q>, Q: ?Sized + Hash + Eq, F: FnOnce() -> K {
+        self.consolidate(probe);
+        self.rehash();
+        let main = self.get_mut_main();
+        if main.contains_key(probe) {
+            // unwrap is safe, checked contains_key already
+            EntryWith::Occupied(main.get_mut(probe).unwrap())
+        } else {
+            EntryWith::Vacant(VacantEntryWith { map: main, make_key })
+        }
+    }
+
+    /// Like `entry`, but leaves a key found in the secondary where it is. The
+    /// returned entry knows which map holds the key and can promote it.
+    pub fn rehash_entry(&mut self, key: K) -> RehashEntry<'_, K, V> {
+        self.rehash();
+        let in_secondary = self.rehashing && self.get_secondary().contains_key(&key);
+        let (main, sec) = self.split_mut();
+        if in_secondary {
+            match sec.entry(key) {
+                hash_map::Entry::Occupied(inner) => RehashEntry::Occupied(OccupiedRehashEntry {
+                    inner,
+                    main: Some(main),
+                }),
+                hash_map::Entry::Vacant(_) => unreachable!(),
+            }
+        } else {
+            match main.entry(key) {
+                hash_map::Entry::Occupied(inner) => RehashEntry::Occupied(OccupiedRehashEntry {
+                    inner,
+                    main: None,
+                }),
+                hash_map::Entry::Vacant(e) => RehashEntry::Vacant(e),
+            }
+        }
+    }
+
+    /// When not rehashing, the secondary is empty, so this is main's iterator
+    /// followed by a pass over an empty map. That pass is a single check
+    /// unless the capacity policy keeps the secondary allocated.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: self.hashmap1.iter().chain(self.hashmap2.iter()),
+            len: self.hashmap1.len() + self.hashmap2.len(),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        self.rehash();
+        let len = self.hashmap1.len() + self.hashmap2.len();
+        IterMut {
+            inner: self.hashmap1.iter_mut().chain(self.hashmap2.iter_mut()),
+            len,
+        }
+    }
+
+    /// Applies `f` to every value, stopping at the first error.
+    pub fn try_for_each_value_mut<E, F>(&mut self, mut f: F) -> Result<(), E>
+            where F: FnMut(&mut V) -> Result<(), E> {
+        for (_, v) in self.iter_mut() {
+            f(v)?;
+        }
+        Ok(())
+    }
+
+    /// Mutable references to the values, in key order. Finishes any
+    /// migration first.
+    pub fn values_mut_sorted(&mut self) -> Vec<&mut V>
+            where K: Ord {
+        self.rehash_until_done();
+        let mut entries: Vec<(&K, &mut V)> = self.get_mut_main().iter_mut().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        entries.into_iter().map(|(_, v)| v).collect()
+    }
+
+    /// Finishes any migration first, so every entry comes from main.
+    pub fn iter_mut_consolidated(&mut self) -> IterMut<'_, K, V> {
+        self.rehash_until_done();
+        self.iter_mut()
+    }
+
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys {
+            inner: self.hashmap1.keys().chain(self.hashmap2.keys()),
+            len: self.hashmap1.len() + self.hashmap2.len(),
+        }
+    }
+
+    /// Scans main, then the secondary, stopping at the first `Some`.
+    pub fn find_map_entry<B, F>(&self, mut f: F) -> Option<B>
+            where F: FnMut(&K, &V) -> Option<B> {
+        self.get_main().iter().find_map(|(k, v)| f(k, v))
+            .or_else(|| self.get_secondary().iter().find_map(|(k, v)| f(k, v)))
+    }
+
+    /// O(n), and allocates a set of references to the values.
+    pub fn distinct_value_count(&self) -> usize
+            where V: Eq + Hash {
+        self.values().collect::<HashSet<_>>().len()
+    }
+
+    /// O(n), unlike key lookups.
+    pub fn contains_value(&self, v: &V) -> bool
+            where V: PartialEq {
+        self.values().any(|x| x == v)
+    }
+
+    /// How many entries are still in the secondary.
+    pub fn remaining_to_rehash(&self) -> usize {
+        self.get_secondary().len()
+    }
+
+    /// Keys that still have to be moved to main, in the order `rehash` will
+    /// move them.
+    pub fn pending_keys(&self) -> hash_map::Keys<'_, K, V> {
+        self.get_secondary().keys()
+    }
+
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values {
+            inner: self.hashmap1.values().chain(self.hashmap2.values()),
+            len: self.hashmap1.len() + self.hashmap2.len(),
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<K, V> RehashingHashMap<K, V>
+    where K: Eq + Hash + Sync, V: Sync
+{
+    pub fn par_iter(&self) -> ParIter<'_, K, V> {
+        ParIter {
+            inner: self.hashmap1.par_iter().chain(self.hashmap2.par_iter()),
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<K, V> RehashingHashMap<K, V>
+    where K: Eq + Hash + Sync, V: Send
+{
+    /// Finishes any migration first, so only main has to be iterated.
+    pub fn par_values_mut(&mut self) -> impl ParallelIterator<Item=&mut V> + '_ {
+        self.rehash_until_done();
+        self.get_mut_main().par_iter_mut().map(|(_, v)| v)
+    }
+}
+
+// formats as a plain map, whichever inner map the entries are in
+struct DebugEntries<'a, K: 'a + Eq + Hash, V: 'a>(&'a RehashingHashMap<K, V>);
+
+impl<'a, K: Eq + Hash + fmt::Debug, V: fmt::Debug> fmt::Debug for DebugEntries<'a, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.0.iter()).finish()
+    }
+}
+
+/// Formats as a plain map. The alternate form (`{:#?}`) also shows the
+/// migration state.
+impl<K, V> fmt::Debug for RehashingHashMap<K, V>
+    where K: Eq + Hash + fmt::Debug, V: fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            f.debug_struct("RehashingHashMap")
+                .field("len", &self.len())
+                .field("is_rehashing", &self.rehashing)
+                .field("remaining_to_rehash", &self.remaining_to_rehash())
+                .field("capacity", &self.capacity())
+                .field("entries", &DebugEntries(self))
+                .finish()
+        } else {
+            DebugEntries(self).fmt(f)
+        }
+    }
+}
+
+impl<K, T> RehashingHashMap<K, Option<T>>
+    where K: Eq + Hash
+{
+    /// Removes every entry whose value is `None`, returning how many there
+    /// were. Like `retain`, this finishes any migration.
+    pub fn retain_some(&mut self) -> usize {
+        self.retain(|_, v| v.is_some())
+    }
+}
+
+impl<K, V> Default for RehashingHashMap<K, V>
+    where K: Eq + Hash
+{
+    fn default() -> RehashingHashMap<K, V> {
+        RehashingHashMap::new()
+    }
+}
+
+impl<K, V> PartialEq for RehashingHashMap<K, V> where K: Eq + Hash, V: PartialEq {
+    fn eq(&self, other: &RehashingHashMap<K, V>) -> bool {
+        // we cannot rehash because `self` and `other` are not immutables!
         // so we should try to see if they are the same manually if they are
         // rehashing
         if !self.is_rehashing() && !other.is_rehashing() {
@@ -253,13 +1382,13 @@ impl<K, V> PartialEq for RehashingHashMap<K, V> where K: Eq + Hash + Clone, V: P
                 return false;
             }
         }
-        return true;
+        true
     }
 }
 
-impl<'a, K, Q: ?Sized, V> Index<&'a Q> for RehashingHashMap<K, V>
-    where K: Eq + Hash + Clone + Borrow<Q>,
-    Q: Eq + Hash,
+impl<K, Q, V> Index<&Q> for RehashingHashMap<K, V>
+    where K: Eq + Hash + Borrow<Q>,
+    Q: ?Sized + Eq + Hash,
 {
     type Output = V;
 
@@ -270,7 +1399,7 @@ impl<'a, K, Q: ?Sized, V> Index<&'a Q> for RehashingHashMap<K, V>
 }
 
 impl<'a, K, V> IntoIterator for &'a RehashingHashMap<K, V>
-    where K: Eq + Hash + Clone
+    where K: Eq + Hash
 {
     type Item = (&'a K, &'a V);
     type IntoIter = Iter<'a, K, V>;
@@ -281,35 +1410,285 @@ impl<'a, K, V> IntoIterator for &'a RehashingHashMap<K, V>
 }
 
 impl<'a, K, V> IntoIterator for &'a mut RehashingHashMap<K, V>
-    where K: Eq + Hash + Clone
+    where K: Eq + Hash
 {
     type Item = (&'a K, &'a mut V);
     type IntoIter = IterMut<'a, K, V>;
 
-    fn into_iter(mut self) -> IterMut<'a, K, V> {
+    fn into_iter(self) -> IterMut<'a, K, V> {
         self.iter_mut()
     }
 }
 
 impl<K, V> FromIterator<(K, V)> for RehashingHashMap<K, V>
-    where K: Eq + Hash + Clone
+    where K: Eq + Hash
 {
     fn from_iter<T: IntoIterator<Item=(K, V)>>(iterable: T) -> RehashingHashMap<K, V> {
         let iter = iterable.into_iter();
-        let lower = iter.size_hint().0;
-        let mut map = RehashingHashMap::with_capacity(lower);
+        // the upper bound is only a limit; a filter may yield far fewer
+        let mut map = RehashingHashMap::with_capacity(iter.size_hint().0);
         map.extend(iter);
         map
     }
 }
 
 impl<K, V> Extend<(K, V)> for RehashingHashMap<K, V>
-    where K: Eq + Hash + Clone
+    where K: Eq + Hash
 {
     fn extend<T: IntoIterator<Item=(K, V)>>(&mut self, iter: T) {
+        // every insert migrates one entry; once the secondary runs dry, one
+        // more step finishes the rehash instead of leaving it to the next call
         for (k, v) in iter {
             self.insert(k, v);
         }
+        if self.rehashing && self.get_secondary().is_empty() {
+            self.rehash();
+        }
+    }
+}
+
+/// How big the secondary is kept while no migration is running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CapacityPolicy {
+    /// The secondary is only allocated while rehashing.
+    Unallocated,
+    /// While not rehashing, the secondary keeps room for this fraction of
+    /// main's capacity, so the next shrink does not have to allocate.
+    SecondaryRatio(f64),
+}
+
+/// What `retain_until` does with an entry, and whether it keeps scanning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetainAction {
+    /// Keeps the entry and moves on to the next one.
+    Keep,
+    /// Removes the entry and moves on to the next one.
+    Remove,
+    /// Keeps the entry and ends the scan.
+    StopKeep,
+    /// Removes the entry and ends the scan.
+    StopRemove,
+}
+
+/// Which of the two inner maps holds a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapSide {
+    /// The map entries are migrated into.
+    Main,
+    /// The map being migrated from; the entry has not moved yet.
+    Secondary,
+}
+
+/// The resize `should_rehash` recommends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RehashReason {
+    /// Main is fuller than the grow threshold.
+    Grow,
+    /// Main is emptier than the shrink threshold.
+    Shrink,
+}
+
+/// Occupancy of the main map, as returned by `bucket_stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BucketStats {
+    /// Main's capacity.
+    pub capacity: usize,
+    /// The number of entries in main.
+    pub len: usize,
+    /// `len / capacity`, or 0 for an unallocated map.
+    pub load_factor: f64,
+}
+
+/// What `shrink_to_fit_reporting` did, in bytes as counted by
+/// `estimated_memory_usage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// The estimate before the shrink.
+    pub before: usize,
+    /// The estimate after it.
+    pub after: usize,
+    /// `before - after`, or 0 if nothing was freed.
+    pub reclaimed: usize,
+}
+
+/// An invariant that `validate` or `from_inner_parts` found broken.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvariantError<K> {
+    /// The first key found stored in both maps.
+    OverlappingKeys(K),
+    /// The secondary has entries but the map is not rehashing.
+    SecondaryNotEmpty,
+}
+
+impl<K: fmt::Debug> fmt::Display for InvariantError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvariantError::OverlappingKeys(ref k) => write!(f, "key {:?} is in both maps", k),
+            InvariantError::SecondaryNotEmpty => write!(f, "the secondary map is not empty while not rehashing"),
+        }
+    }
+}
+
+impl<K: fmt::Debug> Error for InvariantError<K> {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DuplicateKey<K>(pub K);
+
+impl<K: fmt::Debug> fmt::Display for DuplicateKey<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "duplicate key {:?}", self.0)
+    }
+}
+
+impl<K: fmt::Debug> Error for DuplicateKey<K> {}
+
+/// Why `try_get_many_mut` could not hand out the references.
+#[derive(Debug, PartialEq, Eq)]
+pub enum GetManyMutError {
+    /// Indices of the requested keys that are not in the map.
+    MissingKeys(Vec<usize>),
+    /// Indices of two requested keys that are equal.
+    DuplicateKeys(usize, usize),
+}
+
+impl fmt::Display for GetManyMutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GetManyMutError::MissingKeys(ref indices) => write!(f, "keys at {:?} are missing", indices),
+            GetManyMutError::DuplicateKeys(i, j) => write!(f, "keys at {} and {} are the same", i, j),
+        }
+    }
+}
+
+impl Error for GetManyMutError {}
+
+pub enum RehashEntry<'a, K: 'a, V: 'a> {
+    Occupied(OccupiedRehashEntry<'a, K, V>),
+    // new keys always go to main
+    Vacant(hash_map::VacantEntry<'a, K, V>),
+}
+
+impl<'a, K: 'a + Eq + Hash, V: 'a> RehashEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        match *self {
+            RehashEntry::Occupied(ref e) => e.key(),
+            RehashEntry::Vacant(ref e) => e.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            RehashEntry::Occupied(e) => e.into_mut(),
+            RehashEntry::Vacant(e) => e.insert(default),
+        }
+    }
+}
+
+pub enum EntryWith<'a, K: 'a, V: 'a, F> {
+    Occupied(&'a mut V),
+    Vacant(VacantEntryWith<'a, K, V, F>),
+}
+
+impl<'a, K: 'a + Eq + Hash, V: 'a, F: FnOnce() -> K> EntryWith<'a, K, V, F> {
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            EntryWith::Occupied(v) => v,
+            EntryWith::Vacant(e) => e.insert(default),
+        }
+    }
+
+    pub fn or_insert_with<D: FnOnce() -> V>(self, default: D) -> &'a mut V {
+        match self {
+            EntryWith::Occupied(v) => v,
+            EntryWith::Vacant(e) => e.insert(default()),
+        }
+    }
+}
+
+pub struct VacantEntryWith<'a, K: 'a, V: 'a, F> {
+    // always main
+    map: &'a mut HashMap<K, V>,
+    make_key: F,
+}
+
+impl<'a, K: 'a + Eq + Hash, V: 'a, F: FnOnce() -> K> VacantEntryWith<'a, K, V, F> {
+    pub fn insert(self, value: V) -> &'a mut V {
+        self.map.entry((self.make_key)()).or_insert(value)
+    }
+}
+
+pub struct ValueGuard<'a, K: 'a + Eq + Hash, V: 'a> {
+    map: &'a mut RehashingHashMap<K, V>,
+    // only None while dropping
+    entry: Option<(K, V)>,
+}
+
+impl<'a, K: Eq + Hash, V> Deref for ValueGuard<'a, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.entry.as_ref().unwrap().1
+    }
+}
+
+impl<'a, K: Eq + Hash, V> DerefMut for ValueGuard<'a, K, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.entry.as_mut().unwrap().1
+    }
+}
+
+impl<'a, K: Eq + Hash, V> Drop for ValueGuard<'a, K, V> {
+    fn drop(&mut self) {
+        if let Some((k, v)) = self.entry.take() {
+            self.map.get_mut_main().insert(k, v);
+        }
+        self.map.rehash();
+    }
+}
+
+// puts an entry taken out of a map back when dropped
+struct Reinsert<'a, K: 'a + Eq + Hash, V: 'a> {
+    map: &'a mut HashMap<K, V>,
+    entry: Option<(K, V)>,
+}
+
+impl<'a, K: Eq + Hash, V> Drop for Reinsert<'a, K, V> {
+    fn drop(&mut self) {
+        if let Some((k, v)) = self.entry.take() {
+            self.map.insert(k, v);
+        }
+    }
+}
+
+pub struct OccupiedRehashEntry<'a, K: 'a, V: 'a> {
+    inner: hash_map::OccupiedEntry<'a, K, V>,
+    // only set while the entry lives in the secondary
+    main: Option<&'a mut HashMap<K, V>>,
+}
+
+impl<'a, K: 'a + Eq + Hash, V: 'a> OccupiedRehashEntry<'a, K, V> {
+    pub fn key(&self) -> &K { self.inner.key() }
+    pub fn get(&self) -> &V { self.inner.get() }
+    pub fn get_mut(&mut self) -> &mut V { self.inner.get_mut() }
+    pub fn into_mut(self) -> &'a mut V { self.inner.into_mut() }
+
+    pub fn is_in_secondary(&self) -> bool {
+        self.main.is_some()
+    }
+
+    /// Moves the entry to main, if it is not there yet.
+    pub fn rehash_promote(self) -> hash_map::OccupiedEntry<'a, K, V> {
+        match self.main {
+            None => self.inner,
+            Some(main) => {
+                let (k, v) = self.inner.remove_entry();
+                match main.entry(k) {
+                    hash_map::Entry::Vacant(e) => e.insert_entry(v),
+                    // keys are never in both maps
+                    hash_map::Entry::Occupied(_) => unreachable!(),
+                }
+            }
+        }
     }
 }
 
@@ -322,8 +1701,15 @@ pub struct Iter<'a, K: 'a, V: 'a> {
 impl<'a, K, V> Iterator for Iter<'a, K, V> {
     type Item = (&'a K, &'a V);
 
-    #[inline] fn next(&mut self) -> Option<(&'a K, &'a V)> { self.inner.next() }
-    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+    #[inline]
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        let next = self.inner.next();
+        if next.is_some() {
+            self.len -= 1;
+        }
+        next
+    }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
 }
 
 impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
@@ -338,8 +1724,15 @@ pub struct IterMut<'a, K: 'a, V: 'a> {
 impl<'a, K, V> Iterator for IterMut<'a, K, V> {
     type Item = (&'a K, &'a mut V);
 
-    #[inline] fn next(&mut self) -> Option<(&'a K, &'a mut V)> { self.inner.next() }
-    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+    #[inline]
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        let next = self.inner.next();
+        if next.is_some() {
+            self.len -= 1;
+        }
+        next
+    }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
 }
 
 impl<'a, K, V> ExactSizeIterator for IterMut<'a, K, V> {
@@ -355,8 +1748,15 @@ pub struct Keys<'a, K: 'a, V: 'a> {
 impl<'a, K, V> Iterator for Keys<'a, K, V> {
     type Item = &'a K;
 
-    #[inline] fn next(&mut self) -> Option<&'a K> { self.inner.next() }
-    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+    #[inline]
+    fn next(&mut self) -> Option<&'a K> {
+        let next = self.inner.next();
+        if next.is_some() {
+            self.len -= 1;
+        }
+        next
+    }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
 }
 
 impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {
@@ -372,38 +1772,436 @@ pub struct Values<'a, K: 'a, V: 'a> {
 impl<'a, K, V> Iterator for Values<'a, K, V> {
     type Item = &'a V;
 
-    #[inline] fn next(&mut self) -> Option<&'a V> { self.inner.next() }
-    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+    #[inline]
+    fn next(&mut self) -> Option<&'a V> {
+        let next = self.inner.next();
+        if next.is_some() {
+            self.len -= 1;
+        }
+        next
+    }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
 }
 
 impl<'a, K, V> ExactSizeIterator for Values<'a, K, V> {
     #[inline] fn len(&self) -> usize { self.len }
 }
 
-#[test]
-fn capacity() {
-    let mut hash:RehashingHashMap<u8, u8> = RehashingHashMap::with_capacity(20);
-    assert!(hash.capacity() >= 20);
-    hash.reserve(40);
-    assert!(hash.capacity() >= 40);
+pub struct Drain<'a, K: 'a, V: 'a> {
+    inner: Chain<hash_map::Drain<'a, K, V>, hash_map::IntoIter<K, V>>,
+    len: usize,
 }
 
-#[test]
-fn insert() {
-    let mut hash = RehashingHashMap::new();
-    let key = 0;
-    let value1 = 2;
-    let value2 = 3;
+impl<'a, K, V> Iterator for Drain<'a, K, V> {
+    type Item = (K, V);
 
-    assert_eq!(hash.insert(key.clone(), value1.clone()), None);
-    assert_eq!(hash.insert(key.clone(), value2.clone()), Some(value1.clone()));
-    hash.shrink_to_fit();
-    assert!(hash.is_rehashing());
-    assert_eq!(hash.insert(key.clone(), value1.clone()), Some(value2.clone()));
+    #[inline]
+    fn next(&mut self) -> Option<(K, V)> {
+        let next = self.inner.next();
+        if next.is_some() {
+            self.len -= 1;
+        }
+        next
+    }
+    #[inline] fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
+}
+
+impl<'a, K, V> ExactSizeIterator for Drain<'a, K, V> {
+    #[inline] fn len(&self) -> usize { self.len }
+}
+
+impl<'a, K, V> Drop for Drain<'a, K, V> {
+    fn drop(&mut self) {
+        // std's drain already clears on drop, but drop the entries here so
+        // the post-condition does not depend on that
+        for _ in self {}
+    }
+}
+
+#[cfg(feature = "rayon")]
+pub struct ParIter<'a, K: 'a + Sync, V: 'a + Sync> {
+    inner: rayon::iter::Chain<par_hash_map::Iter<'a, K, V>, par_hash_map::Iter<'a, K, V>>,
+}
+
+#[cfg(feature = "rayon")]
+impl<'a, K: Sync, V: Sync> ParallelIterator for ParIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+        where C: UnindexedConsumer<Self::Item>
+    {
+        self.inner.drive_unindexed(consumer)
+    }
+
+    #[inline] fn opt_len(&self) -> Option<usize> { self.inner.opt_len() }
+}
+
+#[test]
+fn cancel_rehash() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    hash.cancel_rehash();
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+    assert_eq!(hash.get_main().len(), len);
+    for i in 0..len {
+        assert_eq!(hash.get(&i).unwrap().clone(), i);
+    }
+    hash.cancel_rehash();
+    assert_eq!(hash.len(), len);
+}
+
+#[test]
+fn capacity() {
+    let mut hash:RehashingHashMap<u8, u8> = RehashingHashMap::with_capacity(20);
+    assert!(hash.capacity() >= 20);
+    hash.reserve(40);
+    assert!(hash.capacity() >= 40);
+}
+
+#[test]
+fn default_without_clone() {
+    #[derive(PartialEq, Eq, Hash, Debug)]
+    struct NonCloneKey(u32);
+
+    let mut hash: RehashingHashMap<NonCloneKey, u32> = RehashingHashMap::default();
+    assert!(hash.is_empty());
+    for i in 0..10 {
+        assert_eq!(hash.insert(NonCloneKey(i), i), None);
+    }
+    hash.shrink_to_fit();
+    while hash.is_rehashing() {
+        hash.rehash();
+    }
+    assert_eq!(hash.len(), 10);
+    assert_eq!(hash.get(&NonCloneKey(3)).unwrap().clone(), 3);
+}
+
+#[test]
+fn non_clone_key() {
+    #[derive(PartialEq, Eq, Hash, Debug)]
+    struct Handle(u32);
+
+    let mut hash: RehashingHashMap<Handle, u32> = (0..100).map(|i| (Handle(i), i)).collect();
+    hash.extend((100..110).map(|i| (Handle(i), i)));
+    hash.shrink_to_fit();
+    for _ in 0..50 {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+
+    assert_eq!(hash.insert(Handle(0), 1000), Some(0));
+    assert_eq!(hash[&Handle(0)], 1000);
+    assert!(hash.contains_key(&Handle(1)));
+    *hash.get_mut(&Handle(1)).unwrap() += 1000;
+    assert_eq!(hash.get(&Handle(1)).unwrap().clone(), 1001);
+    assert_eq!(hash.remove(&Handle(2)), Some(2));
+    for (_, v) in &mut hash {
+        *v += 1;
+    }
+    assert_eq!((&hash).into_iter().count(), 109);
+}
+
+#[test]
+fn send_sync() {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<RehashingHashMap<String, Vec<u8>>>();
+
+    let hash = Arc::new(Mutex::new(RehashingHashMap::new()));
+    let threads: Vec<_> = (0..4).map(|t| {
+        let hash = hash.clone();
+        thread::spawn(move || {
+            for i in 0..100 {
+                let mut hash = hash.lock().unwrap();
+                hash.insert(t * 100 + i, i);
+                if i == 50 {
+                    hash.shrink_to_fit();
+                }
+            }
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(hash.lock().unwrap().len(), 400);
+}
+
+#[test]
+fn auto_grow() {
+    let len = 1000;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.reserve(10);
+    assert!(!hash.is_rehashing());
+
+    hash.set_auto_grow(true);
+    hash.reserve(10 * len);
+    assert!(hash.is_rehashing());
+    assert!(hash.capacity() >= 11 * len);
+    assert_eq!(hash.get_main().len(), 0);
+
+    while hash.is_rehashing() {
+        hash.rehash();
+    }
+    assert!(hash.capacity() >= 11 * len);
+    for i in 0..len {
+        assert_eq!(hash.get(&i).unwrap().clone(), i);
+    }
+
+    // an empty map has nothing to migrate
+    let mut hash = RehashingHashMap::<usize, usize>::new();
+    hash.set_auto_grow(true);
+    hash.reserve(100);
+    assert!(!hash.is_rehashing());
+    assert!(hash.capacity() >= 100);
+    hash.assert_state();
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_values_mut() {
+    fn expensive(v: u64) -> u64 {
+        (0..1000).fold(v, |acc, i| acc.wrapping_mul(31).wrapping_add(i))
+    }
+
+    let len = 10000;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+
+    hash.par_values_mut().for_each(|v| *v = expensive(*v));
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+    for i in 0..len {
+        assert_eq!(hash.get(&i), Some(&expensive(i)));
+    }
+}
+
+#[test]
+fn bucket_stats() {
+    let mut hash = RehashingHashMap::new();
+    assert_eq!(hash.bucket_stats().load_factor, 0.0);
+    for i in 0..1000 {
+        hash.insert(i.clone(), i.clone());
+    }
+    let stats = hash.bucket_stats();
+    assert_eq!(stats.len, 1000);
+    assert_eq!(stats.capacity, hash.capacity());
+    assert!((stats.load_factor - 1000.0 / stats.capacity as f64).abs() < 1e-9);
+    assert!(stats.load_factor > 0.0 && stats.load_factor <= 1.0);
+}
+
+#[test]
+fn reserve_both() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let main_capacity = hash.get_main().capacity();
+    let secondary_capacity = hash.get_secondary().capacity();
+    hash.reserve_both(1000);
+    assert!(hash.get_main().capacity() > main_capacity);
+    assert!(hash.get_secondary().capacity() > secondary_capacity);
+    assert!(hash.get_main().capacity() >= hash.get_main().len() + 1000);
+    assert!(hash.get_secondary().capacity() >= hash.get_secondary().len() + 1000);
+
+    hash.rehash_until_done();
+    hash.reserve_both(1000);
+    hash.assert_state();
+}
+
+#[test]
+fn shrink_to_fit_never_grows() {
+    let mut hash = RehashingHashMap::with_capacity(112);
+    let full = hash.capacity();
+    for i in 0..full {
+        hash.insert(i.clone(), i.clone());
+    }
+    // removals leave tombstones behind, which eat into the reported capacity
+    for i in 0..(full / 5) {
+        hash.remove(&i);
+    }
+    let capacity = hash.capacity();
+    assert!(HashMap::<usize, usize>::with_capacity(hash.len()).capacity() > capacity);
+
+    hash.shrink_to_fit();
+    assert!(!hash.is_rehashing());
+    assert!(hash.capacity() <= capacity);
+    hash.assert_state();
+}
+
+#[test]
+fn shrink_to_fit_migrating() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit_migrating(10);
+    assert!(hash.is_rehashing());
+    assert_eq!(hash.get_main().len(), 10);
+    assert_eq!(hash.pending_keys().count(), len - 10);
+    assert_eq!(hash.len(), len);
+
+    hash.rehash_until_done();
+    hash.shrink_to_fit_migrating(len * 2);
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.get_main().len(), len);
+}
+
+#[test]
+fn shrink_to_fit_empty() {
+    let mut hash: RehashingHashMap<u8, u8> = RehashingHashMap::new();
+    hash.shrink_to_fit();
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.get_secondary().capacity(), 0);
+
+    let mut hash: RehashingHashMap<usize, usize> = RehashingHashMap::with_capacity(1000);
+    hash.insert(1, 1);
+    hash.remove(&1);
+    hash.shrink_to_fit();
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.capacity(), 0);
+    hash.assert_state();
+}
+
+#[test]
+fn with_both_capacities() {
+    let mut hash = RehashingHashMap::with_both_capacities(100, 50);
+    assert!(hash.get_main().capacity() >= 100);
+    assert!(hash.get_secondary().capacity() >= 50);
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+
+    for i in 0..10 {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    assert!(hash.is_rehashing());
+    hash.assert_state();
+
+    let hash = RehashingHashMap::<usize, usize>::with_both_capacities(10, 1000);
+    assert_eq!(hash.get_secondary().capacity(), hash.get_main().capacity());
+    let hash = RehashingHashMap::<usize, usize>::with_both_capacities(0, 50);
+    assert_eq!(hash.get_secondary().capacity(), 0);
+}
+
+#[test]
+fn insert() {
+    let mut hash = RehashingHashMap::new();
+    let key = 0;
+    let value1 = 2;
+    let value2 = 3;
+
+    assert_eq!(hash.insert(key.clone(), value1.clone()), None);
+    assert_eq!(hash.insert(key.clone(), value2.clone()), Some(value1.clone()));
+    hash.shrink_to_fit();
+    assert!(hash.is_rehashing());
+    assert_eq!(hash.insert(key.clone(), value1.clone()), Some(value2.clone()));
     assert!(!hash.is_rehashing());
     hash.assert_state();
 }
 
+#[test]
+fn insert_unique_unchecked() {
+    let len = 10000;
+    let mut unchecked = RehashingHashMap::new();
+    let mut checked = RehashingHashMap::new();
+    for i in 0..len {
+        unchecked.insert_unique_unchecked(i.clone(), i.clone());
+        checked.insert(i.clone(), i.clone());
+    }
+    assert_eq!(unchecked.len(), len);
+    assert!(unchecked == checked);
+
+    unchecked.shrink_to_fit();
+    for i in len..(len * 2) {
+        unchecked.insert_unique_unchecked(i.clone(), i.clone());
+    }
+    unchecked.rehash();
+    assert!(!unchecked.is_rehashing());
+    assert_eq!(unchecked.len(), len * 2);
+    for i in 0..(len * 2) {
+        assert_eq!(unchecked.get(&i).unwrap().clone(), i);
+    }
+}
+
+#[test]
+fn incremental_reserve() {
+    fn main_capacity_midway(incremental: bool) -> usize {
+        let len = 1000;
+        let mut hash = RehashingHashMap::new();
+        hash.set_incremental_reserve(incremental);
+        for i in 0..len {
+            hash.insert(i.clone(), i.clone());
+        }
+        hash.shrink_to_fit();
+        for _ in 0..(len / 4) {
+            hash.rehash();
+        }
+        let capacity = hash.get_main().capacity();
+        hash.rehash_until_done();
+        for i in 0..len {
+            assert_eq!(hash.get(&i).unwrap().clone(), i);
+        }
+        capacity
+    }
+
+    assert!(main_capacity_midway(true) < main_capacity_midway(false));
+}
+
+
+#[test]
+fn insert_during_rehash_reserves() {
+    let len = 5000;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    assert!(hash.get_main().capacity() >= len);
+
+    for i in len..(len * 2) {
+        hash.insert(i.clone(), i.clone());
+        if hash.is_rehashing() {
+            // main always has room for the entries left in the secondary
+            assert!(hash.get_main().capacity() >= hash.len());
+        }
+    }
+    assert_eq!(hash.len(), len * 2);
+    for i in 0..(len * 2) {
+        assert_eq!(hash.get(&i).unwrap().clone(), i);
+    }
+}
+
+#[test]
+fn rehash_if_cheap() {
+    let len = 10;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i, [0u8; 1024]);
+    }
+    hash.shrink_to_fit();
+    assert!(!hash.rehash_if_cheap(64));
+    assert_eq!(hash.pending_keys().count(), len);
+    assert!(hash.rehash_if_cheap(4096));
+    assert_eq!(hash.pending_keys().count(), len - 1);
+
+    hash.rehash_until_done();
+    assert!(!hash.rehash_if_cheap(4096));
+}
+
 #[test]
 fn insert_many_rehash_get() {
     let mut hash = RehashingHashMap::new();
@@ -444,6 +2242,51 @@ fn insert_many_rehash_get() {
     }
 }
 
+#[test]
+fn len_cmp() {
+    let mut hash1 = RehashingHashMap::new();
+    let mut hash2 = RehashingHashMap::new();
+    for i in 0..100 {
+        hash1.insert(i.clone(), i.clone());
+    }
+    for i in 0..50 {
+        hash2.insert(i.clone(), i.clone());
+    }
+    hash1.shrink_to_fit();
+    hash2.shrink_to_fit();
+    for _ in 0..25 {
+        hash1.rehash();
+        hash2.rehash();
+    }
+    assert!(hash1.is_rehashing() && hash2.is_rehashing());
+
+    assert_eq!(hash1.len_cmp(&hash2), Ordering::Greater);
+    assert_eq!(hash2.len_cmp(&hash1), Ordering::Less);
+    for i in 50..100 {
+        hash2.insert(i.clone(), i.clone());
+    }
+    assert_eq!(hash1.len_cmp(&hash2), Ordering::Equal);
+}
+
+#[test]
+fn rehash_returns_done() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    assert!(hash.rehash());
+
+    hash.shrink_to_fit();
+    let mut steps = 0;
+    while !hash.rehash() {
+        steps += 1;
+    }
+    assert_eq!(steps, len);
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.get_main().len(), len);
+}
+
 #[test]
 fn is_empty() {
     let mut hash = RehashingHashMap::new();
@@ -475,71 +2318,1755 @@ fn clear() {
 }
 
 #[test]
-fn remove0() {
+fn recover() {
     let mut hash = RehashingHashMap::new();
-    let key = 0;
-    let value = 2;
-    assert_eq!(hash.insert(key.clone(), value.clone()), None);
+    for i in 0..100 {
+        hash.insert(i.clone(), i.clone());
+    }
     hash.shrink_to_fit();
+    hash.rehash();
     assert!(hash.is_rehashing());
-    assert_eq!(hash.remove(&key).unwrap(), value);
+
+    hash.get_mut_secondary().clear();
+    hash.recover();
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+    assert_eq!(hash.len(), 1);
+}
+
+#[test]
+fn clear_preserves_capacity() {
+    let mut hash = RehashingHashMap::with_capacity(1000);
+    for i in 0..100 {
+        hash.insert(i.clone(), i.clone());
+    }
+    let capacity = hash.capacity();
+    hash.clear();
+    assert_eq!(hash.capacity(), capacity);
+
+    for i in 0..100 {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for _ in 0..50 {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+    let main_capacity = hash.get_main().capacity();
+    hash.clear();
+    hash.assert_state();
+    assert_eq!(hash.capacity(), main_capacity);
+}
+
+#[test]
+fn clear_empty() {
+    let mut hash: RehashingHashMap<u8, u8> = RehashingHashMap::with_capacity(1000);
+    let capacity = hash.capacity();
+    hash.clear();
+    assert_eq!(hash.capacity(), capacity);
+    assert_eq!(hash.get_secondary().capacity(), 0);
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+}
+
+#[test]
+fn remove0() {
+    let mut hash = RehashingHashMap::new();
+    let key = 0;
+    let value = 2;
+    assert_eq!(hash.insert(key.clone(), value.clone()), None);
+    hash.shrink_to_fit();
+    assert!(hash.is_rehashing());
+    assert_eq!(hash.remove(&key).unwrap(), value);
+}
+
+#[test]
+fn remove1() {
+    let mut hash = RehashingHashMap::new();
+    let key = 0;
+    let value = 2;
+    assert_eq!(hash.insert(key.clone(), value.clone()), None);
+    hash.shrink_to_fit();
+    hash.rehash();
+    assert!(hash.is_rehashing());
+    assert_eq!(hash.remove(&key).unwrap(), value);
+}
+
+#[test]
+fn remove2() {
+    let mut hash = RehashingHashMap::new();
+    let key = 0;
+    let value = 2;
+    assert_eq!(hash.insert(key.clone(), value.clone()), None);
+    hash.shrink_to_fit();
+    hash.rehash();
+    hash.rehash();
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.remove(&key).unwrap(), value);
+}
+
+#[test]
+fn auto_shrink() {
+    let len = 1000;
+    let mut hash = RehashingHashMap::with_capacity(len);
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.set_auto_shrink(Some(0.25));
+
+    let threshold = hash.capacity() / 4;
+    for i in 0..len {
+        assert_eq!(hash.remove(&i).unwrap(), i);
+        if hash.is_rehashing() {
+            break;
+        }
+    }
+    assert!(hash.is_rehashing());
+    assert!(hash.len() < threshold);
+}
+
+#[test]
+fn rename() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let in_secondary = *hash.get_secondary().keys().nth(1).unwrap();
+    assert!(hash.rename(&in_secondary, len));
+    assert!(hash.get(&in_secondary).is_none());
+    assert_eq!(hash.get(&len).unwrap().clone(), in_secondary);
+    assert_eq!(hash.len(), len);
+
+    // renaming onto an existing key overwrites it
+    let other = (in_secondary + 1) % len;
+    assert!(hash.rename(&len, other));
+    assert_eq!(hash.get(&other).unwrap().clone(), in_secondary);
+    assert_eq!(hash.len(), len - 1);
+
+    assert!(!hash.rename(&len, len + 1));
+    assert!(hash.get(&(len + 1)).is_none());
+}
+
+#[test]
+fn inner_parts() {
+    let len = 100;
+    let hash = half_migrated(len);
+    let control = RehashingHashMap::from_iter((0..len).map(|i| (i, i)));
+
+    let (hashmap1, hashmap2, is1main, rehashing) = hash.into_inner_parts();
+    assert!(rehashing);
+    let hash = RehashingHashMap::from_inner_parts(hashmap1, hashmap2, is1main, rehashing).unwrap();
+    assert!(hash.is_rehashing());
+    hash.assert_state();
+    assert!(hash == control);
+
+    let main: HashMap<_, _> = (0..10).map(|i| (i, i)).collect();
+    let secondary: HashMap<_, _> = (10..20).map(|i| (i, i)).collect();
+    assert_eq!(RehashingHashMap::from_inner_parts(main.clone(), secondary.clone(), true, false).unwrap_err(),
+               InvariantError::SecondaryNotEmpty);
+    let overlapping: HashMap<_, _> = (9..15).map(|i| (i, i)).collect();
+    assert_eq!(RehashingHashMap::from_inner_parts(main.clone(), overlapping.clone(), true, true).unwrap_err(),
+               InvariantError::OverlappingKeys(9));
+    assert_eq!(RehashingHashMap::from_inner_parts(overlapping, main.clone(), false, true).unwrap_err(),
+               InvariantError::OverlappingKeys(9));
+    let hash = RehashingHashMap::from_inner_parts(main.clone(), HashMap::new(), true, true).unwrap();
+    assert_eq!(hash.len(), 10);
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+    let hash = RehashingHashMap::from_inner_parts(main, HashMap::with_capacity(100), true, false).unwrap();
+    hash.assert_state();
+    assert_eq!(hash.len(), 10);
+}
+
+#[test]
+fn maintenance_tick() {
+    let len = 1000;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    for i in 0..(len - 100) {
+        hash.remove(&i);
+    }
+    let capacity = hash.capacity();
+
+    hash.maintenance_tick(Duration::from_millis(10), 0.25);
+    assert!(hash.is_rehashing());
+    let mut ticks = 0;
+    while hash.is_rehashing() {
+        hash.maintenance_tick(Duration::from_millis(10), 0.25);
+        ticks += 1;
+        assert!(ticks < 10000);
+    }
+    assert!(hash.capacity() < capacity);
+
+    // compact now, so further ticks have nothing to do
+    hash.maintenance_tick(Duration::from_millis(10), 0.25);
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.len(), 100);
+    for i in (len - 100)..len {
+        assert_eq!(hash.get(&i).unwrap().clone(), i);
+    }
+}
+
+#[test]
+fn entry_secondary() {
+    let main: HashMap<_, _> = (0..10).map(|i| (i, i)).collect();
+    let secondary: HashMap<_, _> = (10..20).map(|i| (i, i)).collect();
+    let mut hash = RehashingHashMap::from_inner_parts(main, secondary, true, true).unwrap();
+
+    hash.entry_secondary(20).unwrap().or_insert(20);
+    assert!(hash.get_secondary().contains_key(&20));
+    assert!(hash.entry_secondary(0).is_none());
+
+    while hash.is_rehashing() {
+        hash.rehash();
+    }
+    assert!(hash.entry_secondary(21).is_none());
+    assert_eq!(hash.get_main().len(), 21);
+    for i in 0..21 {
+        assert_eq!(hash.get(&i).unwrap().clone(), i);
+    }
+}
+
+#[test]
+fn iterator() {
+    let len = 100;
+    let mut hash = RehashingHashMap::with_capacity(len);
+    let mut control = HashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+        control.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+
+    assert_eq!(hash.iter().len(), len);
+    for (_, i) in hash.iter() {
+        control.remove(i);
+    }
+    assert!(control.is_empty());
+}
+
+#[test]
+fn size_hint() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let mut iter = hash.iter();
+    assert_eq!(iter.size_hint(), (len, Some(len)));
+    for i in 0..len {
+        assert_eq!(iter.len(), len - i);
+        assert_eq!(iter.size_hint(), (len - i, Some(len - i)));
+        iter.next().unwrap();
+    }
+    assert_eq!(iter.size_hint(), (0, Some(0)));
+    assert!(iter.next().is_none());
+    assert_eq!(hash.keys().size_hint(), (len, Some(len)));
+    assert_eq!(hash.values().size_hint(), (len, Some(len)));
+    assert_eq!(hash.iter_mut().size_hint(), (len, Some(len)));
+}
+
+#[test]
+fn iter_mut() {
+    let len = 100;
+    let mut hash = RehashingHashMap::with_capacity(len);
+    let mut control = HashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+        control.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+
+    assert_eq!(hash.iter_mut().len(), len);
+    for (_, i) in hash.iter_mut() {
+        control.remove(i);
+        *i *= 2;
+    }
+    assert!(control.is_empty());
+
+    // make sure mutability was saved
+    for i in 0..len {
+        assert_eq!(hash.get(&i).unwrap().clone(), i * 2);
+    }
+}
+
+#[test]
+fn try_for_each_value_mut() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let mut seen = 0;
+    let result = hash.try_for_each_value_mut(|v| {
+        seen += 1;
+        if seen == 50 {
+            return Err(seen);
+        }
+        *v += 1000;
+        Ok(())
+    });
+    assert_eq!(result, Err(50));
+    assert_eq!(hash.values().filter(|v| **v >= 1000).count(), 49);
+
+    assert_eq!(hash.try_for_each_value_mut(|v| { *v %= 1000; Ok::<(), ()>(()) }), Ok(()));
+    assert!(hash.values().all(|v| *v < len));
+}
+
+#[test]
+fn iter_mut_consolidated() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    assert_eq!(hash.iter_mut_consolidated().len(), len);
+    assert!(hash.get_secondary().is_empty());
+    for (_, v) in hash.iter_mut_consolidated() {
+        *v *= 2;
+    }
+    assert_eq!(hash.get_main().len(), len);
+    assert!(!hash.is_rehashing());
+    for i in 0..len {
+        assert_eq!(hash.get(&i).unwrap().clone(), i * 2);
+    }
+}
+
+#[test]
+fn keys() {
+    let len = 100;
+    let mut hash = RehashingHashMap::with_capacity(len);
+    let mut control = HashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+        control.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+
+    assert_eq!(hash.keys().len(), len);
+    for i in hash.keys() {
+        control.remove(i);
+    }
+    assert!(control.is_empty());
+}
+
+#[test]
+fn values() {
+    let len = 100;
+    let mut hash = RehashingHashMap::with_capacity(len);
+    let mut control = HashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+        control.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+
+    assert_eq!(hash.values().len(), len);
+    for i in hash.values() {
+        control.remove(i);
+    }
+    assert!(control.is_empty());
+}
+
+#[test]
+fn pending_keys() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    assert_eq!(hash.pending_keys().count(), 0);
+    hash.shrink_to_fit();
+    assert_eq!(hash.pending_keys().count(), len);
+
+    for i in 0..10 {
+        let next = *hash.pending_keys().next().unwrap();
+        hash.rehash();
+        assert_eq!(hash.pending_keys().count(), len - i - 1);
+        assert!(hash.pending_keys().all(|k| *k != next));
+    }
+
+    let removed = *hash.pending_keys().nth(5).unwrap();
+    hash.remove(&removed);
+    assert!(hash.pending_keys().all(|k| *k != removed));
+
+    while hash.is_rehashing() {
+        hash.rehash();
+    }
+    assert_eq!(hash.pending_keys().count(), 0);
+}
+
+#[test]
+fn retain() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    hash.retain(|k, v| {
+        *v *= 2;
+        k % 2 == 0
+    });
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+    assert_eq!(hash.len(), len / 2);
+    for i in 0..len {
+        if i % 2 == 0 {
+            assert_eq!(hash.get(&i).unwrap().clone(), i * 2);
+        } else {
+            assert!(hash.get(&i).is_none());
+        }
+    }
+}
+
+#[test]
+fn retain_count() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    assert_eq!(hash.retain(|k, _| k % 2 == 0), 50);
+    assert_eq!(hash.len(), 50);
+
+    hash.shrink_to_fit();
+    for _ in 0..25 {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+    assert_eq!(hash.retain(|k, _| k % 4 == 0), 25);
+    assert_eq!(hash.retain(|_, _| true), 0);
+}
+
+#[test]
+fn remove_where() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    assert_eq!(hash.remove_where(|_, v| *v >= 70), 30);
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.len(), 70);
+    assert!(hash.values().all(|v| *v < 70));
+    assert_eq!(hash.remove_where(|_, v| *v >= 70), 0);
+}
+
+#[test]
+fn contains_value() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone() * 2);
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+
+    let in_main = *hash.get_main().values().next().unwrap();
+    let in_secondary = *hash.get_secondary().values().next().unwrap();
+    assert!(hash.contains_value(&in_main));
+    assert!(hash.contains_value(&in_secondary));
+    assert!(!hash.contains_value(&1));
+}
+
+#[test]
+fn entry() {
+    let len = 100;
+    let mut hash = RehashingHashMap::with_capacity(len);
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+
+    // modifying main
+    {
+        let v = hash.entry(0).or_insert(100); // updating
+        *v += 1;
+    }
+    hash.entry(len).or_insert(len); // inserting
+
+    hash.shrink_to_fit();
+    // modifying secondary
+    assert!(hash.is_rehashing());
+    {
+        let v = hash.entry(1).or_insert(100); // updating
+        *v += 1;
+    }
+    hash.entry(len + 1).or_insert(len + 1); // inserting
+
+    while hash.is_rehashing() {
+        hash.rehash();
+    }
+
+    // modifying the new main
+    {
+        let v = hash.entry(2).or_insert(100); // updating
+        *v += 1;
+    }
+    hash.entry(len + 2).or_insert(len + 2); // inserting
+
+    for i in 0..(len + 3) {
+        assert_eq!(hash.get(&i).unwrap().clone(), if i <= 2 { i + 1 } else { i });
+    }
+}
+
+#[test]
+fn entry_or_insert_with_key() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone() * 10);
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+
+    let in_secondary = *hash.get_secondary().keys().nth(1).unwrap();
+    match hash.entry(in_secondary) {
+        hash_map::Entry::Occupied(e) => assert_eq!(e.key(), &in_secondary),
+        hash_map::Entry::Vacant(_) => panic!("expected an occupied entry"),
+    }
+    assert!(hash.get_main().contains_key(&in_secondary));
+    assert!(!hash.get_secondary().contains_key(&in_secondary));
+
+    let in_secondary = *hash.get_secondary().keys().nth(1).unwrap();
+    assert_eq!(*hash.entry(in_secondary).or_insert_with_key(|_| panic!("already present")), in_secondary * 10);
+    assert_eq!(*hash.entry(len).or_insert_with_key(|k| k * 10), len * 10);
+    for i in 0..(len + 1) {
+        assert_eq!(hash.get(&i).unwrap().clone(), i * 10);
+    }
+}
+
+#[test]
+fn rehash_entry() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let in_secondary = *hash.get_secondary().keys().nth(1).unwrap();
+    match hash.rehash_entry(in_secondary) {
+        RehashEntry::Occupied(e) => {
+            assert!(e.is_in_secondary());
+            assert_eq!(e.get().clone(), in_secondary);
+            let mut e = e.rehash_promote();
+            *e.get_mut() += 1000;
+        }
+        RehashEntry::Vacant(_) => panic!("expected an occupied entry"),
+    }
+    assert!(hash.get_main().contains_key(&in_secondary));
+    assert!(!hash.get_secondary().contains_key(&in_secondary));
+    assert_eq!(hash.get(&in_secondary).unwrap().clone(), in_secondary + 1000);
+
+    let in_main = *hash.get_main().keys().next().unwrap();
+    match hash.rehash_entry(in_main) {
+        RehashEntry::Occupied(e) => assert!(!e.is_in_secondary()),
+        RehashEntry::Vacant(_) => panic!("expected an occupied entry"),
+    }
+    assert_eq!(*hash.rehash_entry(len).or_insert(len), len);
+    assert!(hash.get_main().contains_key(&len));
+}
+
+#[test]
+fn get_or_insert() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let in_secondary = *hash.get_secondary().keys().nth(1).unwrap();
+    assert_eq!(hash.get_or_insert(in_secondary, 1000).clone(), in_secondary);
+    assert_eq!(hash.get_or_insert(len, 1000).clone(), 1000);
+    assert_eq!(hash.get_or_insert(len, 2000).clone(), 1000);
+    assert_eq!(hash.len(), len + 1);
+}
+
+#[test]
+fn try_get_many_mut() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let in_main = *hash.get_main().keys().next().unwrap();
+    let in_secondary = *hash.get_secondary().keys().nth(1).unwrap();
+    assert_eq!(hash.try_get_many_mut([&in_main, &len, &in_secondary]).unwrap_err(),
+               GetManyMutError::MissingKeys(vec![1]));
+    assert_eq!(hash.try_get_many_mut([&in_main, &in_secondary, &in_main]).unwrap_err(),
+               GetManyMutError::DuplicateKeys(0, 2));
+
+    let in_secondary = *hash.get_secondary().keys().nth(1).unwrap();
+    {
+        let [a, b] = hash.try_get_many_mut([&in_main, &in_secondary]).unwrap();
+        std::mem::swap(a, b);
+    }
+    assert_eq!(hash.get(&in_main).unwrap().clone(), in_secondary);
+    assert_eq!(hash.get(&in_secondary).unwrap().clone(), in_main);
+}
+
+#[test]
+fn increment() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let in_secondary = *hash.get_secondary().keys().nth(1).unwrap();
+    assert_eq!(*hash.increment(in_secondary, 5), in_secondary + 5);
+    assert_eq!(*hash.increment(in_secondary, 5), in_secondary + 10);
+    assert_eq!(*hash.increment(len, 3), 3);
+    assert_eq!(*hash.increment(len, 3), 6);
+    assert_eq!(hash.get(&in_secondary).unwrap().clone(), in_secondary + 10);
+    assert_eq!(hash.get(&len).unwrap().clone(), 6);
+}
+
+#[test]
+fn entry_not_rehashing() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    let capacity = hash.capacity();
+    *hash.entry(0).or_insert_with(|| 1000) += 1;
+    hash.entry(len).or_insert_with(|| len);
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+    assert_eq!(hash.capacity(), capacity);
+    assert_eq!(hash.get(&0).unwrap().clone(), 1);
+    assert_eq!(hash.get(&len).unwrap().clone(), len);
+
+    hash.shrink_to_fit();
+    let pending = hash.pending_keys().count();
+    *hash.entry(1).or_insert_with(|| 1000) += 1;
+    assert!(hash.pending_keys().count() < pending);
+    assert_eq!(hash.get(&1).unwrap().clone(), 2);
+}
+
+#[test]
+fn upsert() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let in_secondary = *hash.get_secondary().keys().nth(1).unwrap();
+    hash.upsert(in_secondary, || panic!("already present"), |v| *v += 1000);
+    assert_eq!(hash.get(&in_secondary).unwrap().clone(), in_secondary + 1000);
+    hash.upsert(len, || 1000, |_| panic!("not present"));
+    assert_eq!(hash.get(&len).unwrap().clone(), 1000);
+    assert_eq!(hash.len(), len + 1);
+}
+
+#[test]
+fn contains_key() {
+    let len = 100;
+    let mut hash = RehashingHashMap::with_capacity(len);
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+
+    for i in 0..len {
+        assert!(hash.contains_key(&i));
+    }
+    assert!(!hash.contains_key(&(len + 1)));
+}
+
+#[test]
+fn get_mut0() {
+    let mut hash = RehashingHashMap::new();
+    let value = 1;
+    {
+        hash.insert(value.clone(), value.clone());
+        hash.shrink_to_fit();
+        assert!(hash.is_rehashing());
+        let val = hash.get_mut(&value).unwrap();
+        *val += 1;
+    }
+    assert_eq!(hash.get(&value).unwrap().clone(), 2);
+}
+
+#[test]
+fn get_mut1() {
+    let mut hash = RehashingHashMap::new();
+    let value = 1;
+    {
+        hash.insert(value.clone(), value.clone());
+        hash.shrink_to_fit();
+        hash.rehash();
+        assert!(hash.is_rehashing());
+        let val = hash.get_mut(&value).unwrap();
+        *val += 1;
+    }
+    assert_eq!(hash.get(&value).unwrap().clone(), 2);
+}
+
+#[test]
+fn get_mut2() {
+    let mut hash = RehashingHashMap::new();
+    let value = 1;
+    {
+        hash.insert(value.clone(), value.clone());
+        hash.shrink_to_fit();
+        hash.rehash();
+        hash.rehash();
+        assert!(!hash.is_rehashing());
+        let val = hash.get_mut(&value).unwrap();
+        *val += 1;
+    }
+    assert_eq!(hash.get(&value).unwrap().clone(), 2);
+}
+
+#[test]
+fn update() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let in_main = *hash.get_main().keys().next().unwrap();
+    let in_secondary = *hash.get_secondary().keys().next().unwrap();
+    assert!(hash.update(&in_main, |v| *v += 1000));
+    assert!(hash.update(&in_secondary, |v| *v += 1000));
+    assert!(hash.get_main().contains_key(&in_secondary));
+    assert!(!hash.update(&len, |v| *v += 1000));
+
+    assert_eq!(hash.get(&in_main).unwrap().clone(), in_main + 1000);
+    assert_eq!(hash.get(&in_secondary).unwrap().clone(), in_secondary + 1000);
+    assert!(hash.get(&len).is_none());
+}
+
+#[test]
+fn get_with_side() {
+    let mut hash = RehashingHashMap::new();
+    for i in 0..10 {
+        hash.insert(i.clone(), i.clone());
+    }
+    assert_eq!(hash.get_with_side(&0), Some((&0, MapSide::Main)));
+    hash.shrink_to_fit();
+
+    let next = *hash.get_secondary().keys().next().unwrap();
+    assert_eq!(hash.get_with_side(&next), Some((&next, MapSide::Secondary)));
+    hash.rehash();
+    assert_eq!(hash.get_with_side(&next), Some((&next, MapSide::Main)));
+    assert_eq!(hash.get_with_side(&10), None);
+}
+
+#[test]
+fn debug_key_state() {
+    let len = 100;
+    let hash = half_migrated(len);
+
+    let mut in_main = 0;
+    for i in 0..len {
+        match hash.debug_key_state(&i) {
+            (true, false) => in_main += 1,
+            (false, true) => {}
+            state => panic!("unexpected state {:?} for {}", state, i),
+        }
+    }
+    assert_eq!(in_main, len / 2);
+    assert_eq!(hash.debug_key_state(&len), (false, false));
+}
+
+#[test]
+fn get_cow() {
+    let len = 100;
+    let hash = half_migrated(len);
+
+    let in_main = *hash.get_main().keys().next().unwrap();
+    let in_secondary = *hash.get_secondary().keys().next().unwrap();
+    for k in [in_main, in_secondary].iter() {
+        match hash.get_cow(k).unwrap() {
+            Cow::Borrowed(v) => {
+                assert_eq!(v, k);
+                assert!(std::ptr::eq(v, hash.get(k).unwrap()));
+            }
+            Cow::Owned(_) => panic!("expected a borrowed value"),
+        }
+    }
+    assert!(hash.get_cow(&len).is_none());
+}
+
+#[test]
+fn with_values_mut() {
+    let len = 200;
+    let mut hash = half_migrated(len);
+
+    let keys: Vec<usize> = (0..100).map(|i| i * 2).collect();
+    let refs: Vec<&usize> = keys.iter().collect();
+    hash.with_values_mut(&refs, |k, v| {
+        assert_eq!(k, v);
+        *v += 1000;
+    });
+
+    for i in 0..len {
+        let expected = if i % 2 == 0 { i + 1000 } else { i };
+        assert_eq!(hash.get(&i).unwrap().clone(), expected);
+    }
+
+    // a panicking closure does not lose the entry it was handed
+    let pending = *hash.pending_keys().last().unwrap();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        hash.with_values_mut(&[&pending], |_, _| panic!("boom"));
+    }));
+    assert!(result.is_err());
+    assert_eq!(hash.len(), len);
+    assert_eq!(hash.get(&pending), Some(&pending));
+    hash.assert_state();
+}
+
+#[cfg(test)]
+thread_local! {
+    static HASH_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// `0..len` mapped to itself, with half of it migrated by a shrink
+#[cfg(test)]
+fn half_migrated(len: usize) -> RehashingHashMap<usize, usize> {
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i, i);
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+    hash
+}
+
+// a key that counts how many times it gets hashed on the current thread
+#[cfg(test)]
+#[derive(PartialEq, Eq, Debug)]
+struct CountedKey(usize);
+
+#[cfg(test)]
+impl Hash for CountedKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        HASH_CALLS.with(|c| c.set(c.get() + 1));
+        self.0.hash(state);
+    }
+}
+
+#[cfg(test)]
+fn hash_calls<F: FnOnce()>(f: F) -> usize {
+    HASH_CALLS.with(|c| c.set(0));
+    f();
+    HASH_CALLS.with(|c| c.get())
+}
+
+#[test]
+fn get_mut_hashes_once() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(CountedKey(i), i);
+    }
+    assert_eq!(hash_calls(|| *hash.get_mut(&CountedKey(0)).unwrap() += 1), 1);
+
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+
+    // the rehash step itself hashes the key it moves to main
+    // not 0, which was bumped already
+    let in_main = hash.get_main().keys().find(|k| k.0 != 0).unwrap().0;
+    assert_eq!(hash_calls(|| *hash.get_mut(&CountedKey(in_main)).unwrap() += 1), 2);
+    let in_secondary = hash.get_secondary().keys().filter(|k| k.0 != 0).nth(1).unwrap().0;
+    assert_eq!(hash_calls(|| *hash.get_mut(&CountedKey(in_secondary)).unwrap() += 1), 3);
+    assert!(hash.get_mut(&CountedKey(len)).is_none());
+
+    for i in 0..len {
+        let bumped = i == 0 || i == in_main || i == in_secondary;
+        assert_eq!(hash.get(&CountedKey(i)).unwrap().clone(), if bumped { i + 1 } else { i });
+    }
+}
+
+#[test]
+fn get_mut_in_place() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let pending = hash.pending_keys().count();
+    let in_secondary = *hash.get_secondary().keys().next().unwrap();
+    *hash.get_mut_in_place(&in_secondary).unwrap() += 1000;
+    assert_eq!(hash.get_with_side(&in_secondary), Some((&(in_secondary + 1000), MapSide::Secondary)));
+    assert_eq!(hash.pending_keys().count(), pending);
+
+    let in_main = *hash.get_main().keys().next().unwrap();
+    *hash.get_mut_in_place(&in_main).unwrap() += 1000;
+    assert_eq!(hash.get_with_side(&in_main), Some((&(in_main + 1000), MapSide::Main)));
+    assert!(hash.get_mut_in_place(&len).is_none());
+}
+
+#[test]
+fn extend_hashes_once() {
+    let len = 100;
+    let mut hash = RehashingHashMap::with_capacity(len);
+    assert_eq!(hash_calls(|| hash.extend((0..len).map(|i| (CountedKey(i), i)))), len);
+    assert_eq!(hash_calls(|| hash.extend((0..len).map(|i| (CountedKey(i), i + 1)))), len);
+    assert!(!hash.is_rehashing());
+    for i in 0..len {
+        assert_eq!(hash.get(&CountedKey(i)).unwrap().clone(), i + 1);
+    }
+
+    hash.shrink_to_fit();
+    assert!(hash.is_rehashing());
+    let key = hash.get_secondary().keys().nth(1).unwrap().0;
+    assert_eq!(hash.insert(CountedKey(key), 0), Some(key + 1));
+    assert_eq!(hash.len(), len);
+}
+
+#[test]
+fn eq() {
+    let mut hash1 = RehashingHashMap::new();
+    let mut hash2 = RehashingHashMap::new();
+
+    for i in 0..100 {
+        hash1.insert(i.clone(), i.clone());
+        hash2.insert(i.clone(), i.clone());
+    }
+    hash1.shrink_to_fit();
+    hash2.shrink_to_fit();
+    while hash2.is_rehashing() {
+        assert_eq!(hash1, hash2);
+        hash2.rehash();
+    }
+    hash2.shrink_to_fit();
+    hash2.insert(101, 101);
+    assert!(hash1 != hash2);
+}
+
+#[test]
+fn clone_compact() {
+    let len = 100;
+    let hash = half_migrated(len);
+
+    let clone = hash.clone_compact();
+    assert!(!clone.is_rehashing());
+    clone.assert_state();
+    assert!(clone.is1main);
+    assert_eq!(clone.hashmap1.len(), len);
+    assert!(clone.capacity() >= len);
+    assert!(clone == hash);
+}
+
+#[test]
+fn index() {
+    let mut hash = RehashingHashMap::new();
+
+    for i in 0..100 {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for i in 0..100 {
+        hash.rehash();
+        assert_eq!(hash[&i], i);
+    }
+}
+
+#[test]
+fn into_iter() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    let mut control = HashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+        control.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+
+    for (k, v) in hash.into_iter() {
+        assert_eq!(&control.remove(k).unwrap(), v);
+    }
+    assert_eq!(control.len(), 0);
+}
+
+#[test]
+fn extend() {
+    let mut hash = RehashingHashMap::new();
+    hash.extend(vec![(1, 1), (2, 2), (3, 3)]);
+    assert_eq!(hash.len(), 3);
+}
+
+#[test]
+fn from_iter() {
+    let hash = RehashingHashMap::from_iter(vec![(1, 1), (2, 2), (3, 3)]);
+    assert_eq!(hash.len(), 3);
+}
+
+#[test]
+fn from_iter_reserves_lower_bound() {
+    let len = 5000;
+    let expected = HashMap::<usize, usize>::with_capacity(len).capacity();
+
+    let hash = RehashingHashMap::from_iter((0..len).map(|i| (i, i)));
+    assert_eq!(hash.capacity(), expected);
+
+    // a sparse filter must not reserve for the whole source
+    let hash = RehashingHashMap::from_iter((0..len).map(|i| (i, i)).filter(|&(i, _)| i < 3));
+    assert_eq!(hash.len(), 3);
+    assert!(hash.capacity() < expected);
+
+    // an upper bound of usize::MAX must not be reserved either
+    let hash = RehashingHashMap::from_iter((0..usize::MAX).take_while(|&i| i < 3).map(|i| (i, i)));
+    assert_eq!(hash.len(), 3);
+}
+
+#[test]
+fn try_from_iter_unique() {
+    let hash = RehashingHashMap::try_from_iter_unique(vec![(1, 1), (2, 2), (3, 3)]).unwrap();
+    assert_eq!(hash.len(), 3);
+    assert_eq!(hash[&2], 2);
+
+    let err = RehashingHashMap::try_from_iter_unique(vec![(1, 1), (2, 2), (1, 3), (2, 4)]).unwrap_err();
+    assert_eq!(err, DuplicateKey(1));
+
+    let hash = RehashingHashMap::try_from_iter_unique((0..usize::MAX).take_while(|&i| i < 3).map(|i| (i, i))).unwrap();
+    assert_eq!(hash.len(), 3);
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_iter() {
+    let len = 10000;
+    let hash = half_migrated(len);
+
+    let sequential: usize = hash.values().sum();
+    let parallel: usize = hash.par_iter().map(|(_, v)| *v).sum();
+    assert_eq!(parallel, sequential);
+    assert_eq!(hash.par_iter().count(), len);
+}
+
+#[test]
+fn remove_with_side() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+
+    // the step taken by remove_with_side migrates the first pending key, so
+    // pick the last one
+    let pending = *hash.pending_keys().last().unwrap();
+    assert_eq!(hash.remove_with_side(&pending), Some((pending, MapSide::Secondary)));
+    let migrated = *hash.pending_keys().next().unwrap();
+    hash.rehash();
+    assert_eq!(hash.remove_with_side(&migrated), Some((migrated, MapSide::Main)));
+    assert_eq!(hash.remove_with_side(&migrated), None);
+
+    hash.rehash_until_done();
+    let k = *hash.keys().next().unwrap();
+    assert_eq!(hash.remove_with_side(&k), Some((k, MapSide::Main)));
+    assert_eq!(hash.len(), len - 3);
+    hash.assert_state();
+}
+
+#[test]
+fn should_rehash() {
+    let mut hash = RehashingHashMap::new();
+    assert_eq!(hash.should_rehash(), None);
+    for i in 0..1000 {
+        hash.insert(i, i);
+    }
+    while hash.len() < hash.capacity() {
+        let len = hash.len();
+        hash.insert(len, len);
+    }
+    assert_eq!(hash.should_rehash(), Some(RehashReason::Grow));
+
+    // tombstones left by removals lower the reported capacity, so leave
+    // plenty of margin below the threshold
+    let len = hash.len();
+    for i in 0..(len - 10) {
+        hash.remove(&i);
+    }
+    assert_eq!(hash.should_rehash(), Some(RehashReason::Shrink));
+    hash.shrink_to_fit();
+    assert!(hash.is_rehashing());
+    assert_eq!(hash.should_rehash(), None);
+
+    hash.set_rehash_thresholds(0.0, 1.0);
+    hash.rehash_until_done();
+    assert_eq!(hash.should_rehash(), None);
+}
+
+#[test]
+fn drain() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    let mut drained = hash.drain().collect::<Vec<_>>();
+    drained.sort();
+    assert_eq!(drained, (0..len).map(|i| (i, i)).collect::<Vec<_>>());
+    assert!(hash.is_empty());
+    hash.assert_state();
+
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+    assert_eq!(hash.drain().take(10).count(), 10);
+    assert!(hash.is_empty());
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+    hash.insert(1, 1);
+    assert_eq!(hash.get(&1), Some(&1));
+}
+
+#[test]
+fn get_mut_guard() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for _ in 0..10 {
+        hash.rehash();
+    }
+    let migrated = *hash.get_main().keys().next().unwrap();
+    let remaining = hash.remaining_to_rehash();
+    {
+        let mut guard = hash.get_mut_guard(&migrated).unwrap();
+        *guard += 1000;
+    }
+    assert_eq!(hash.remaining_to_rehash(), remaining - 1);
+    assert_eq!(hash.get(&migrated), Some(&(migrated + 1000)));
+
+    // a value from the secondary is put back into main
+    let pending = *hash.pending_keys().last().unwrap();
+    *hash.get_mut_guard(&pending).unwrap() += 1000;
+    assert_eq!(hash.debug_key_state(&pending), (true, false));
+
+    hash.rehash_until_done();
+    hash.insert(len, len);
+    *hash.get_mut_guard(&len).unwrap() += 1000;
+    assert_eq!(hash.get(&len), Some(&(len + 1000)));
+    assert!(hash.get_mut_guard(&(len + 1)).is_none());
+    assert_eq!(hash.len(), len + 1);
+    hash.assert_state();
+}
+
+#[test]
+fn extend_during_rehash() {
+    let len = 1000;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    assert!(hash.is_rehashing());
+    hash.extend((len..(2 * len)).map(|i| (i, i)));
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.len(), 2 * len);
+    hash.assert_state();
+    for i in 0..(2 * len) {
+        assert_eq!(hash.get(&i), Some(&i));
+    }
+}
+
+#[test]
+fn shrink_to_fit_reporting() {
+    let len = 1000;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    for i in 10..len {
+        hash.remove(&i);
+    }
+    let report = hash.shrink_to_fit_reporting();
+    assert!(report.reclaimed > 0);
+    assert!(report.after < report.before);
+    assert_eq!(report.after, hash.estimated_memory_usage());
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.len(), 10);
+
+    let mut empty = RehashingHashMap::<usize, usize>::new();
+    assert_eq!(empty.shrink_to_fit_reporting().reclaimed, 0);
+}
+
+#[test]
+fn get_or_try_insert_with() {
+    let len = 10;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+
+    assert_eq!(hash.get_or_try_insert_with(len, || Err("io")), Err("io"));
+    assert!(!hash.contains_key(&len));
+    assert_eq!(hash.len(), len);
+
+    let pending = *hash.pending_keys().last().unwrap();
+    assert_eq!(hash.get_or_try_insert_with(pending, || Err("io")), Ok(&mut pending.clone()));
+    assert_eq!(hash.debug_key_state(&pending), (true, false));
+
+    assert_eq!(hash.get_or_try_insert_with(len, || Ok::<_, ()>(100)), Ok(&mut 100));
+    assert_eq!(hash.get(&len), Some(&100));
+    assert_eq!(hash.len(), len + 1);
+}
+
+#[test]
+fn insert_tracked() {
+    let len = 10;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        assert_eq!(hash.insert_tracked(i.clone(), i.clone()), (None, false));
+    }
+    assert_eq!(hash.insert_tracked(0, 1), (Some(0), false));
+
+    hash.shrink_to_fit();
+    let remaining = hash.remaining_to_rehash();
+    assert_eq!(hash.insert_tracked(len, len), (None, true));
+    assert_eq!(hash.remaining_to_rehash(), remaining - 1);
+    assert_eq!(hash.insert_tracked(len, 0), (Some(len), true));
+}
+
+#[test]
+fn take_all() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+    let capacity = hash.get_main().capacity();
+
+    let all = hash.take_all();
+    assert_eq!(all.len(), len);
+    for i in 0..len {
+        assert_eq!(all.get(&i), Some(&i));
+    }
+    assert!(hash.is_empty());
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.capacity(), capacity);
+    hash.assert_state();
+}
+
+#[test]
+fn reads_do_not_advance_rehash() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    let remaining = hash.remaining_to_rehash();
+    for _ in 0..10 {
+        for i in 0..len {
+            assert_eq!(hash.get(&i), Some(&i));
+            assert!(hash.contains_key(&i));
+        }
+    }
+    // reads take &self and cannot count towards the stall limit, but the
+    // stall shows in remaining_to_rehash
+    assert_eq!(hash.remaining_to_rehash(), remaining);
+    hash.assert_state();
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "rehash has not advanced")]
+fn stalled_rehash() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    for i in 0..STALL_LIMIT {
+        *hash.get_mut_in_place(&(i % len)).unwrap() += 1;
+    }
+    hash.assert_state();
+}
+
+#[test]
+fn modify_or_insert_default() {
+    let mut hash: RehashingHashMap<u32, Vec<u32>> = RehashingHashMap::new();
+    for i in 0..100 {
+        hash.modify_or_insert_default(i % 10, |v| v.push(i));
+    }
+    hash.shrink_to_fit();
+    assert!(hash.is_rehashing());
+    for i in 100..200 {
+        hash.modify_or_insert_default(i % 10, |v| v.push(i));
+    }
+    assert_eq!(hash.len(), 10);
+    for k in 0..10 {
+        assert_eq!(hash.get(&k).unwrap(), &(0..20).map(|i| i * 10 + k).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn validate() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    assert_eq!(hash.validate(), Ok(()));
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    assert_eq!(hash.validate(), Ok(()));
+    hash.shrink_to_fit();
+    assert_eq!(hash.validate(), Ok(()));
+    for i in 0..len {
+        hash.rehash();
+        if i % 10 == 0 {
+            hash.insert(i.clone(), i.clone());
+        }
+        assert_eq!(hash.validate(), Ok(()));
+    }
+    hash.rehash();
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.validate(), Ok(()));
+    hash.clear();
+    assert_eq!(hash.validate(), Ok(()));
+
+    hash.insert(0, 0);
+    hash.get_mut_secondary().insert(0, 0);
+    assert_eq!(hash.validate(), Err(InvariantError::SecondaryNotEmpty));
+    hash.rehashing = true;
+    assert_eq!(hash.validate(), Err(InvariantError::OverlappingKeys(&0)));
+}
+
+#[test]
+fn reserve_returning() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    let capacity = hash.reserve_returning(1000);
+    assert!(capacity >= len + 1000);
+    assert_eq!(capacity, hash.capacity());
+
+    hash.shrink_to_fit();
+    assert!(hash.reserve_returning(10) >= len + 10);
+    hash.set_auto_grow(true);
+    hash.rehash_until_done();
+    assert!(hash.reserve_returning(10000) >= len + 10000);
+}
+
+#[test]
+fn get_pending() {
+    let len = 10;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    assert_eq!(hash.get_pending(&0), Some((&0, false)));
+    hash.shrink_to_fit();
+
+    let next = *hash.pending_keys().next().unwrap();
+    assert_eq!(hash.get_pending(&next), Some((&next, true)));
+    hash.rehash();
+    assert_eq!(hash.get_pending(&next), Some((&next, false)));
+    assert_eq!(hash.get_pending(&len), None);
+}
+
+#[test]
+fn insert_bounded() {
+    let max_len = 10;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..max_len {
+        assert_eq!(hash.insert_bounded(i.clone(), i.clone(), max_len), Ok(None));
+    }
+    assert_eq!(hash.insert_bounded(max_len, max_len, max_len), Err(max_len));
+    assert_eq!(hash.insert_bounded(0, 100, max_len), Ok(Some(0)));
+
+    hash.shrink_to_fit();
+    let pending = *hash.pending_keys().last().unwrap();
+    let old = hash.get(&pending).cloned();
+    assert_eq!(hash.insert_bounded(pending, 100, max_len), Ok(old));
+    assert_eq!(hash.insert_bounded(max_len, max_len, max_len), Err(max_len));
+    assert_eq!(hash.len(), max_len);
+    assert!(!hash.contains_key(&max_len));
+}
+
+#[test]
+fn drain_range() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let mut drained = hash.drain_range(..50);
+    drained.sort();
+    assert_eq!(drained, (0..50).map(|i| (i, i)).collect::<Vec<_>>());
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+    let mut remaining = hash.keys().cloned().collect::<Vec<_>>();
+    remaining.sort();
+    assert_eq!(remaining, (50..len).collect::<Vec<_>>());
+
+    assert_eq!(hash.drain_range(90..=95).len(), 6);
+    assert_eq!(hash.len(), 44);
+}
+
+#[test]
+fn shrink_to_fit_during_auto_grow() {
+    let len = 1000;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.set_auto_grow(true);
+    hash.reserve(100 * len);
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+    let grown = hash.get_main().capacity();
+
+    hash.shrink_to_fit();
+    assert!(hash.get_main().capacity() < grown);
+    hash.rehash_until_done();
+    hash.assert_state();
+    assert_eq!(hash.validate(), Ok(()));
+    assert_eq!(hash.len(), len);
+    for i in 0..len {
+        assert_eq!(hash.get(&i), Some(&i));
+    }
+
+    // a shrink in progress is not restarted
+    for i in 0..(len / 2) {
+        hash.remove(&i);
+    }
+    hash.shrink_to_fit();
+    assert!(hash.is_rehashing());
+    hash.rehash();
+    let remaining = hash.remaining_to_rehash();
+    hash.shrink_to_fit();
+    assert_eq!(hash.remaining_to_rehash(), remaining);
+
+    // nor finished when removals mid-migration make main look too big
+    let keys: Vec<usize> = hash.keys().cloned().take(hash.len() - 10).collect();
+    for key in keys {
+        hash.remove(&key);
+    }
+    let remaining = hash.remaining_to_rehash();
+    assert!(remaining > 0);
+    hash.shrink_to_fit();
+    assert_eq!(hash.remaining_to_rehash(), remaining);
+}
+
+#[test]
+fn entry_tracked() {
+    let len = 10;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    assert!(!hash.entry_tracked(0).1);
+    hash.shrink_to_fit();
+
+    let pending = *hash.pending_keys().next().unwrap();
+    {
+        let (entry, moved) = hash.entry_tracked(pending);
+        assert!(moved);
+        *entry.or_insert(0) += 100;
+    }
+    assert_eq!(hash.debug_key_state(&pending), (true, false));
+    assert_eq!(hash.get(&pending), Some(&(pending + 100)));
+    assert!(!hash.entry_tracked(pending).1);
+    assert!(!hash.entry_tracked(len).1);
+}
+
+#[test]
+fn find_map_entry() {
+    let len = 100;
+    let hash = half_migrated(len);
+    // the last one, so all of main is scanned
+    let migrated = *hash.get_main().keys().last().unwrap();
+
+    let mut visited = Vec::new();
+    let found = hash.find_map_entry(|k, v| {
+        visited.push(*k);
+        if *k == migrated { Some(*v * 2) } else { None }
+    });
+    assert_eq!(found, Some(migrated * 2));
+    assert_eq!(visited.len(), hash.get_main().len());
+    assert!(visited.iter().all(|k| !hash.pending_keys().any(|p| p == k)));
+
+    let pending = *hash.pending_keys().last().unwrap();
+    assert_eq!(hash.find_map_entry(|k, _| if *k == pending { Some(*k) } else { None }), Some(pending));
+    assert_eq!(hash.find_map_entry(|k, _| if *k == len { Some(*k) } else { None }), None);
+}
+
+#[test]
+fn insert_all() {
+    let len = 10;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+
+    let displaced = hash.insert_all(vec![(5, 50), (10, 100), (15, 150), (5, 500), (9, 90)]);
+    let mut expected = HashMap::new();
+    expected.insert(5, 5);
+    expected.insert(9, 9);
+    assert_eq!(displaced, expected);
+    assert_eq!(hash.get(&5), Some(&500));
+    assert_eq!(hash.len(), len + 2);
+
+    // rolling back
+    hash.extend(displaced);
+    hash.remove(&10);
+    hash.remove(&15);
+    assert_eq!(hash.len(), len);
+    for i in 0..len {
+        assert_eq!(hash.get(&i), Some(&i));
+    }
+}
+
+#[test]
+fn try_get_many_mut_across_maps() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+    let mut keys: Vec<usize> = hash.get_main().keys().take(4).cloned().collect();
+    keys.extend(hash.pending_keys().skip(1).take(4));
+
+    {
+        let refs = [&keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6], &keys[7]];
+        let values = hash.try_get_many_mut(refs).unwrap();
+        for v in values {
+            *v += 1000;
+        }
+    }
+    for k in keys.iter() {
+        assert_eq!(hash.debug_key_state(k), (true, false));
+        assert_eq!(hash.get(k), Some(&(k + 1000)));
+    }
+    assert_eq!(hash.len(), len);
+}
+
+#[test]
+fn debug() {
+    let mut hash = RehashingHashMap::new();
+    hash.insert(1, 1);
+    assert_eq!(format!("{:?}", hash), "{1: 1}");
+
+    for i in 2..10 {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    let plain = format!("{:?}", hash);
+    assert!(!plain.contains("is_rehashing"));
+    assert!(!plain.contains("remaining"));
+    let alternate = format!("{:#?}", hash);
+    assert!(alternate.contains("is_rehashing: true"));
+    assert!(alternate.contains("remaining_to_rehash: 9"));
+    assert!(alternate.contains("capacity"));
+}
+
+#[test]
+fn retain_until() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    let mut calls = 0;
+    let mut removed = Vec::new();
+    let count = hash.retain_until(|k, _| {
+        calls += 1;
+        removed.push(*k);
+        if removed.len() == 10 { RetainAction::StopRemove } else { RetainAction::Remove }
+    });
+    assert_eq!(count, 10);
+    assert_eq!(calls, 10);
+    assert_eq!(hash.len(), len - 10);
+    for i in 0..len {
+        assert_eq!(hash.get(&i).is_some(), !removed.contains(&i));
+    }
+    hash.assert_state();
+
+    assert_eq!(hash.retain_until(|_, _| RetainAction::StopKeep), 0);
+    assert_eq!(hash.len(), len - 10);
+    let mut calls = 0;
+    assert_eq!(hash.retain_until(|_, _| {
+        calls += 1;
+        if calls == 60 { RetainAction::StopKeep } else { RetainAction::Keep }
+    }), 0);
+    assert_eq!(calls, 60);
+    assert_eq!(hash.len(), len - 10);
+    for i in 0..len {
+        assert_eq!(hash.get(&i).is_some(), !removed.contains(&i));
+    }
+    assert_eq!(hash.retain_until(|k, _| if *k % 2 == 0 { RetainAction::Remove } else { RetainAction::Keep }),
+               (0..len).filter(|i| i % 2 == 0 && !removed.contains(i)).count());
+    assert!(hash.is_rehashing());
+    hash.assert_state();
+}
+
+#[test]
+fn distinct_value_count() {
+    let len = 100;
+    let mut hash = RehashingHashMap::new();
+    assert_eq!(hash.distinct_value_count(), 0);
+    for i in 0..len {
+        hash.insert(i.clone(), i % 7);
+    }
+    assert_eq!(hash.distinct_value_count(), 7);
+    hash.shrink_to_fit();
+    for _ in 0..(len / 2) {
+        hash.rehash();
+    }
+    assert!(hash.is_rehashing());
+    assert_eq!(hash.distinct_value_count(), 7);
+    hash.insert(len, 7);
+    assert_eq!(hash.distinct_value_count(), 8);
+}
+
+#[test]
+fn shrink_to_fit_checked() {
+    let len = 1000;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    for i in 10..len {
+        hash.remove(&i);
+    }
+    assert!(hash.shrink_to_fit_checked());
+    assert!(hash.is_rehashing());
+    // the migration already heads to the right size
+    assert!(!hash.shrink_to_fit_checked());
+
+    hash.rehash_until_done();
+    assert!(!hash.shrink_to_fit_checked());
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.len(), 10);
+    hash.assert_state();
+}
+
+#[test]
+fn entry_with() {
+    let len = 10;
+    let mut hash: RehashingHashMap<String, usize> = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(format!("key{}", i), i);
+    }
+    hash.shrink_to_fit();
+
+    let pending = hash.pending_keys().last().unwrap().clone();
+    let mut called = false;
+    *hash.entry_with(pending.as_str(), || { called = true; pending.clone() }).or_insert(0) += 100;
+    assert!(!called);
+    assert_eq!(hash.debug_key_state(pending.as_str()), (true, false));
+
+    let mut called = false;
+    *hash.entry_with("new", || { called = true; "new".to_owned() }).or_insert_with(|| 5) += 1;
+    assert!(called);
+    assert_eq!(hash.get("new"), Some(&6));
+    assert_eq!(hash.len(), len + 1);
+}
+
+#[test]
+fn get_or() {
+    let len = 10;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    let default = 100;
+    assert_eq!(hash.get_or(&1, &default), &1);
+    assert_eq!(hash.get_or(&len, &default), &default);
+    hash.shrink_to_fit();
+    let pending = *hash.pending_keys().next().unwrap();
+    assert_eq!(hash.get_or(&pending, &default), &pending);
+    assert_eq!(hash.get_or(&len, &default), &default);
+}
+
+#[test]
+fn rehash_to_cap() {
+    let len = 5000;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    hash.shrink_to_fit();
+    assert_eq!(hash.remaining_to_rehash(), len);
+
+    assert_eq!(hash.rehash_to_cap(1000), len - 1000);
+    assert_eq!(hash.remaining_to_rehash(), 1000);
+    assert_eq!(hash.rehash_to_cap(1000), 0);
+    assert_eq!(hash.rehash_to_cap(0), 1000);
+    assert_eq!(hash.len(), len);
+    assert_eq!(hash.rehash_to_cap(0), 0);
 }
 
 #[test]
-fn remove1() {
-    let mut hash = RehashingHashMap::new();
-    let key = 0;
-    let value = 2;
-    assert_eq!(hash.insert(key.clone(), value.clone()), None);
-    hash.shrink_to_fit();
-    hash.rehash();
+fn clone() {
+    let len = 100;
+    let hash = half_migrated(len);
+
+    let mut clone = hash.clone();
+    assert!(clone.is_rehashing());
+    assert_eq!(clone.remaining_to_rehash(), hash.remaining_to_rehash());
+    assert!(clone == hash);
+    clone.assert_state();
+    clone.rehash_until_done();
+    assert!(clone == hash);
     assert!(hash.is_rehashing());
-    assert_eq!(hash.remove(&key).unwrap(), value);
 }
 
 #[test]
-fn remove2() {
-    let mut hash = RehashingHashMap::new();
-    let key = 0;
-    let value = 2;
-    assert_eq!(hash.insert(key.clone(), value.clone()), None);
-    hash.shrink_to_fit();
-    hash.rehash();
-    hash.rehash();
+fn replace_contents() {
+    let len = 100;
+    let mut hash = half_migrated(len);
+
+    hash.replace_contents((1000..1200).map(|i| (i, i)));
     assert!(!hash.is_rehashing());
-    assert_eq!(hash.remove(&key).unwrap(), value);
+    hash.assert_state();
+    assert_eq!(hash.len(), 200);
+    for i in 0..len {
+        assert!(!hash.contains_key(&i));
+    }
+    for i in 1000..1200 {
+        assert_eq!(hash.get(&i), Some(&i));
+    }
+    hash.replace_contents((0..usize::MAX).take_while(|&i| i < 3).map(|i| (i, i)));
+    assert_eq!(hash.len(), 3);
 }
 
 #[test]
-fn iterator() {
-    let len = 100;
-    let mut hash = RehashingHashMap::with_capacity(len);
-    let mut control = HashMap::new();
+fn replace_value() {
+    let len = 10;
+    let mut hash = RehashingHashMap::new();
     for i in 0..len {
         hash.insert(i.clone(), i.clone());
-        control.insert(i.clone(), i.clone());
     }
+    assert_eq!(hash.replace_value(&0, 100), Some(0));
     hash.shrink_to_fit();
-    for _ in 0..(len / 2) {
-        hash.rehash();
-    }
-    assert!(hash.is_rehashing());
 
-    assert_eq!(hash.iter().len(), len);
-    for (_, i) in hash.iter() {
-        control.remove(&i);
-    }
-    assert!(control.is_empty());
+    let pending = *hash.pending_keys().next().unwrap();
+    let old = *hash.get(&pending).unwrap();
+    assert_eq!(hash.replace_value(&pending, 1000), Some(old));
+    assert_eq!(hash.get_with_side(&pending), Some((&1000, MapSide::Secondary)));
+    assert_eq!(hash.replace_value(&len, 1000), None);
+    assert!(!hash.contains_key(&len));
+    assert_eq!(hash.len(), len);
 }
 
 #[test]
-fn iter_mut() {
+fn values_mut_sorted() {
     let len = 100;
-    let mut hash = RehashingHashMap::with_capacity(len);
-    let mut control = HashMap::new();
+    let mut hash = RehashingHashMap::new();
     for i in 0..len {
-        hash.insert(i.clone(), i.clone());
-        control.insert(i.clone(), i.clone());
+        hash.insert(i.clone(), 1);
     }
     hash.shrink_to_fit();
     for _ in 0..(len / 2) {
@@ -547,230 +4074,290 @@ fn iter_mut() {
     }
     assert!(hash.is_rehashing());
 
-    assert_eq!(hash.iter_mut().len(), len);
-    for (_, i) in hash.iter_mut() {
-        control.remove(&i);
-        *i *= 2;
+    let mut total = 0;
+    for v in hash.values_mut_sorted() {
+        total += *v;
+        *v = total;
     }
-    assert!(control.is_empty());
-
-    // make sure mutability was saved
+    assert!(!hash.is_rehashing());
     for i in 0..len {
-        assert_eq!(hash.get(&i).unwrap().clone(), i * 2);
+        assert_eq!(hash.get(&i), Some(&(i + 1)));
     }
 }
 
 #[test]
-fn keys() {
-    let len = 100;
-    let mut hash = RehashingHashMap::with_capacity(len);
-    let mut control = HashMap::new();
+fn target_capacity() {
+    let len = 1000;
+    let mut hash = RehashingHashMap::new();
+    assert_eq!(hash.target_capacity(), 0);
     for i in 0..len {
         hash.insert(i.clone(), i.clone());
-        control.insert(i.clone(), i.clone());
+    }
+    assert_eq!(hash.target_capacity(), hash.capacity());
+    for i in 100..len {
+        hash.remove(&i);
     }
     hash.shrink_to_fit();
-    for _ in 0..(len / 2) {
+    for _ in 0..10 {
         hash.rehash();
     }
     assert!(hash.is_rehashing());
+    let target = hash.target_capacity();
+    assert!(target < hash.capacity());
 
-    assert_eq!(hash.keys().len(), len);
-    for i in hash.keys() {
-        control.remove(&i);
+    hash.rehash_until_done();
+    assert_eq!(hash.capacity(), target);
+    assert_eq!(hash.target_capacity(), target);
+
+    // with incremental reserve main only grows as entries move in
+    hash.set_incremental_reserve(true);
+    for i in len..(10 * len) {
+        hash.insert(i, i);
     }
-    assert!(control.is_empty());
+    hash.shrink_to_fit();
+    hash.rehash();
+    assert!(hash.is_rehashing());
+    assert!(hash.get_main().capacity() < hash.len());
+    assert!(hash.target_capacity() >= hash.len());
 }
 
 #[test]
-fn values() {
-    let len = 100;
-    let mut hash = RehashingHashMap::with_capacity(len);
-    let mut control = HashMap::new();
+fn modify_or_insert() {
+    let len = 10;
+    let mut hash = RehashingHashMap::new();
     for i in 0..len {
         hash.insert(i.clone(), i.clone());
-        control.insert(i.clone(), i.clone());
     }
     hash.shrink_to_fit();
-    for _ in 0..(len / 2) {
-        hash.rehash();
+
+    let pending = *hash.pending_keys().last().unwrap();
+    hash.modify_or_insert(pending, |v| *v += 100, 0);
+    assert_eq!(hash.get_with_side(&pending), Some((&(pending + 100), MapSide::Main)));
+
+    hash.modify_or_insert(len, |v| *v += 100, 0);
+    assert_eq!(hash.get(&len), Some(&0));
+    hash.modify_or_insert(len, |v| *v += 100, 0);
+    assert_eq!(hash.get(&len), Some(&100));
+    assert_eq!(hash.len(), len + 1);
+}
+
+#[test]
+fn rehash_watermarks() {
+    let len = 1000;
+    let mut hash = RehashingHashMap::new();
+    hash.set_auto_grow(true);
+    hash.set_rehash_watermarks(0.25, 0.9);
+    hash.reserve(len);
+    hash.assert_state();
+    assert!(!hash.is_rehashing());
+    let capacity = hash.capacity();
+    assert!(len as f64 <= 0.9 * capacity as f64);
+
+    // stay between the watermarks. A reserve here could still grow: the
+    // tombstones removals leave behind count against std's capacity
+    let low = capacity * 3 / 10;
+    let high = capacity * 8 / 10;
+    for i in 0..high {
+        hash.insert(i, i);
+    }
+    for _ in 0..5 {
+        for i in low..high {
+            hash.remove(&i);
+            assert!(!hash.is_rehashing());
+        }
+        for i in low..high {
+            hash.insert(i, i);
+            assert!(!hash.is_rehashing());
+        }
     }
-    assert!(hash.is_rehashing());
 
-    assert_eq!(hash.values().len(), len);
-    for i in hash.values() {
-        control.remove(&i);
+    // a shrink leaves room below the grow watermark
+    let mut shrunk = false;
+    for i in 10..high {
+        hash.remove(&i);
+        shrunk |= hash.is_rehashing();
     }
-    assert!(control.is_empty());
+    assert!(shrunk);
+    hash.rehash_until_done();
+    assert!(10.0 <= 0.9 * hash.capacity() as f64);
+    hash.reserve(1);
+    assert!(!hash.is_rehashing());
+
+    // and a grow past it aims back below it
+    let capacity = hash.capacity();
+    hash.reserve(capacity);
+    assert!(hash.is_rehashing());
+    hash.rehash_until_done();
+    assert!((10 + capacity) as f64 <= 0.9 * hash.capacity() as f64);
 }
 
 #[test]
-fn entry() {
+fn iter_not_rehashing() {
     let len = 100;
-    let mut hash = RehashingHashMap::with_capacity(len);
+    let mut hash = RehashingHashMap::new();
     for i in 0..len {
         hash.insert(i.clone(), i.clone());
     }
+    assert!(!hash.is_rehashing());
+    assert_eq!(hash.iter().len(), hash.len());
+    assert_eq!(hash.iter().len(), hash.get_main().len());
+    assert!(hash.iter().eq(hash.get_main().iter()));
+}
 
-    // modifying main
-    {
-        let v = hash.entry(0).or_insert(100); // updating
-        *v += 1;
+#[test]
+fn capacity_policy() {
+    let len = 1000;
+    let mut hash = RehashingHashMap::new();
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
     }
-    hash.entry(len).or_insert(len); // inserting
+    hash.set_capacity_policy(CapacityPolicy::SecondaryRatio(0.5));
+    assert!(hash.get_secondary().capacity() >= hash.get_main().capacity() / 2);
+    hash.assert_state();
 
+    for i in 100..len {
+        hash.remove(&i);
+    }
     hash.shrink_to_fit();
-    // modifying secondary
     assert!(hash.is_rehashing());
-    {
-        let v = hash.entry(1).or_insert(100); // updating
-        *v += 1;
+    hash.rehash_until_done();
+    hash.assert_state();
+    let main = hash.get_main().capacity();
+    assert_eq!(main, HashMap::<usize, usize>::with_capacity(100).capacity());
+    assert!(hash.get_secondary().capacity() >= main / 2);
+    assert!(hash.get_secondary().capacity() < main);
+    for i in 0..100 {
+        assert_eq!(hash.get(&i), Some(&i));
     }
-    hash.entry(len + 1).or_insert(len + 1); // inserting
 
-    while hash.is_rehashing() {
-        hash.rehash();
+    // draining keeps the idle secondary, whether or not it was rehashing
+    let secondary = hash.get_secondary().capacity();
+    assert_eq!(hash.drain().count(), 100);
+    assert_eq!(hash.get_secondary().capacity(), secondary);
+    hash.assert_state();
+    for i in 0..len {
+        hash.insert(i, i);
     }
-
-    // modifying the new main
-    {
-        let v = hash.entry(2).or_insert(100); // updating
-        *v += 1;
+    for i in 100..len {
+        hash.remove(&i);
     }
-    hash.entry(len + 2).or_insert(len + 2); // inserting
+    hash.shrink_to_fit();
+    hash.rehash();
+    assert!(hash.is_rehashing());
+    assert_eq!(hash.drain_keys().count(), 100);
+    assert!(hash.get_secondary().capacity() >= hash.get_main().capacity() / 2);
+    hash.assert_state();
 
-    for i in 0..(len + 3) {
-        assert_eq!(hash.get(&i).unwrap().clone(), if i <= 2 { i + 1 } else { i });
-    }
+    hash.set_capacity_policy(CapacityPolicy::Unallocated);
+    assert_eq!(hash.get_secondary().capacity(), 0);
+    hash.assert_state();
 }
 
 #[test]
-fn contains_key() {
+fn drain_keys() {
+    use std::rc::Rc;
+
     let len = 100;
-    let mut hash = RehashingHashMap::with_capacity(len);
+    let counter = Rc::new(());
+    let mut hash = RehashingHashMap::new();
     for i in 0..len {
-        hash.insert(i.clone(), i.clone());
+        hash.insert(i.clone(), counter.clone());
     }
     hash.shrink_to_fit();
     for _ in 0..(len / 2) {
         hash.rehash();
     }
     assert!(hash.is_rehashing());
+    assert_eq!(Rc::strong_count(&counter), len + 1);
 
-    for i in 0..len {
-        assert!(hash.contains_key(&i));
-    }
-    assert!(!hash.contains_key(&(len + 1)));
-}
-
-#[test]
-fn get_mut0() {
-    let mut hash = RehashingHashMap::new();
-    let value = 1;
-    {
-        hash.insert(value.clone(), value.clone());
-        hash.shrink_to_fit();
-        assert!(hash.is_rehashing());
-        let val = hash.get_mut(&value).unwrap();
-        *val += 1;
-    }
-    assert_eq!(hash.get(&value).unwrap().clone(), 2);
+    let mut keys: Vec<usize> = hash.drain_keys().collect();
+    keys.sort();
+    assert_eq!(keys, (0..len).collect::<Vec<_>>());
+    assert!(hash.is_empty());
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+    assert_eq!(Rc::strong_count(&counter), 1);
 }
 
 #[test]
-fn get_mut1() {
-    let mut hash = RehashingHashMap::new();
-    let value = 1;
-    {
-        hash.insert(value.clone(), value.clone());
-        hash.shrink_to_fit();
-        hash.rehash();
-        assert!(hash.is_rehashing());
-        let val = hash.get_mut(&value).unwrap();
-        *val += 1;
-    }
-    assert_eq!(hash.get(&value).unwrap().clone(), 2);
-}
+fn merge_from_hashmap() {
+    let len = 10000;
+    let source: HashMap<usize, usize> = (0..len).map(|i| (i, i)).collect();
+    let capacity = source.capacity();
 
-#[test]
-fn get_mut2() {
     let mut hash = RehashingHashMap::new();
-    let value = 1;
-    {
-        hash.insert(value.clone(), value.clone());
-        hash.shrink_to_fit();
-        hash.rehash();
-        hash.rehash();
-        assert!(!hash.is_rehashing());
-        let val = hash.get_mut(&value).unwrap();
-        *val += 1;
-    }
-    assert_eq!(hash.get(&value).unwrap().clone(), 2);
-}
-
-#[test]
-fn eq() {
-    let mut hash1 = RehashingHashMap::new();
-    let mut hash2 = RehashingHashMap::new();
-
-    for i in 0..100 {
-        hash1.insert(i.clone(), i.clone());
-        hash2.insert(i.clone(), i.clone());
-    }
-    hash1.shrink_to_fit();
-    hash2.shrink_to_fit();
-    while hash2.is_rehashing() {
-        assert_eq!(hash1, hash2);
-        hash2.rehash();
-    }
-    hash2.shrink_to_fit();
-    hash2.insert(101, 101);
-    assert!(hash1 != hash2);
-}
+    hash.insert(0, 0);
+    hash.shrink_to_fit();
+    hash.rehash_until_done();
+    hash.remove(&0);
+    assert!(!hash.is1main);
 
-#[test]
-fn index() {
-    let mut hash = RehashingHashMap::new();
+    hash.merge_from_hashmap(source);
+    assert_eq!(hash.capacity(), capacity);
+    assert_eq!(hash.len(), len);
+    hash.assert_state();
 
-    for i in 0..100 {
-        hash.insert(i.clone(), i.clone());
-    }
-    hash.shrink_to_fit();
-    for i in 0..100 {
-        hash.rehash();
-        assert_eq!(hash[&i], i);
-    }
+    let mut other: HashMap<usize, usize> = HashMap::new();
+    other.insert(0, 100);
+    other.insert(len, len);
+    hash.merge_from_hashmap(other);
+    assert_eq!(hash.len(), len + 1);
+    assert_eq!(hash.get(&0), Some(&100));
+    assert_eq!(hash.get(&len), Some(&len));
 }
 
 #[test]
-fn into_iter() {
+fn retain_some() {
     let len = 100;
-    let mut hash = RehashingHashMap::new();
-    let mut control = HashMap::new();
+    let mut hash: RehashingHashMap<u32, Option<u32>> = RehashingHashMap::new();
     for i in 0..len {
-        hash.insert(i.clone(), i.clone());
-        control.insert(i.clone(), i.clone());
+        hash.insert(i, if i % 3 == 0 { None } else { Some(i) });
     }
     hash.shrink_to_fit();
     for _ in 0..(len / 2) {
         hash.rehash();
     }
+    assert!(hash.is_rehashing());
 
-    for (k, v) in hash.into_iter() {
-        assert_eq!(&control.remove(&k).unwrap(), v);
+    assert_eq!(hash.retain_some(), 34);
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
+    assert_eq!(hash.len(), 66);
+    for i in 0..len {
+        assert_eq!(hash.get(&i).cloned(), if i % 3 == 0 { None } else { Some(Some(i)) });
     }
-    assert_eq!(control.len(), 0);
+    assert_eq!(hash.retain_some(), 0);
 }
 
 #[test]
-fn extend() {
+fn compact_if_sparse() {
+    let len = 1000;
     let mut hash = RehashingHashMap::new();
-    hash.extend(vec![(1, 1), (2, 2), (3, 3)]);
-    assert_eq!(hash.len(), 3);
+    for i in 0..len {
+        hash.insert(i.clone(), i.clone());
+    }
+    assert!(!hash.compact_if_sparse(0.1));
+    for i in 10..len {
+        hash.remove(&i);
+    }
+    assert!(hash.compact_if_sparse(0.1));
+    assert!(hash.is_rehashing());
+    hash.rehash_until_done();
+
+    // emptying a map frees main instead of starting a migration
+    hash.clear();
+    hash.reserve(len);
+    assert!(!hash.compact_if_sparse(0.1));
+    assert!(!hash.is_rehashing());
+    hash.assert_state();
 }
 
 #[test]
-fn from_iter() {
-    let hash = RehashingHashMap::from_iter(vec![(1, 1), (2, 2), (3, 3)]);
-    assert_eq!(hash.len(), 3);
+fn allocated_capacity() {
+    for capacity in 0..5000 {
+        assert_eq!(RehashingHashMap::<usize, usize>::allocated_capacity(capacity),
+                   HashMap::<usize, usize>::with_capacity(capacity).capacity(), "capacity {}", capacity);
+        assert_eq!(RehashingHashMap::<u8, ()>::allocated_capacity(capacity),
+                   HashMap::<u8, ()>::with_capacity(capacity).capacity(), "capacity {}", capacity);
+    }
 }